@@ -0,0 +1,265 @@
+//! Which tag keys mean which field of a listen. Every container spells its tags differently, and
+//! mpv passes most of them on as they are, so keys are matched the way `filter::field_name` makes
+//! them: ignoring case, spaces and underscores.
+
+use std::collections::HashMap;
+
+use crate::filter::field_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Artist,
+    Artists,
+    Album,
+    AlbumArtist,
+    AlbumArtists,
+    Genre,
+    TrackNumber,
+    DiscNumber,
+    Isrc,
+    RecordingMbid,
+    ReleaseMbid,
+    ArtistMbids,
+    ReleaseGroupMbid,
+    TrackMbid,
+    WorkMbids,
+}
+
+impl Field {
+    /// The field's name in `listenbrainz-tag-aliases`
+    fn parse(name: &str) -> Option<Self> {
+        Some(match field_name(name).as_str() {
+            "title" => Self::Title,
+            "artist" => Self::Artist,
+            "artists" => Self::Artists,
+            "album" => Self::Album,
+            "albumartist" => Self::AlbumArtist,
+            "albumartists" => Self::AlbumArtists,
+            "genre" => Self::Genre,
+            "tracknumber" => Self::TrackNumber,
+            "discnumber" => Self::DiscNumber,
+            "isrc" => Self::Isrc,
+            "recordingmbid" => Self::RecordingMbid,
+            "releasembid" => Self::ReleaseMbid,
+            "artistmbids" => Self::ArtistMbids,
+            "releasegroupmbid" => Self::ReleaseGroupMbid,
+            "trackmbid" => Self::TrackMbid,
+            "workmbids" => Self::WorkMbids,
+            _ => return None,
+        })
+    }
+}
+
+/// The keys Picard, ffmpeg and mpv use, by container. Every key is listed once, in the spelling
+/// of the first container that uses it.
+const ALIASES: &[(&str, Field)] = &[
+    // Vorbis comments (FLAC, Ogg Vorbis, Opus), which Matroska tags mostly share. `ALBUMARTIST`
+    // is also ffmpeg's `album_artist` and APEv2's `Album Artist`, and `MUSICBRAINZ_TRACKID` the
+    // ID3 `TXXX` description and iTunes freeform name `MusicBrainz Track Id`.
+    ("TITLE", Field::Title),
+    ("ARTIST", Field::Artist),
+    ("ARTISTS", Field::Artists),
+    ("ALBUM", Field::Album),
+    ("ALBUMARTIST", Field::AlbumArtist),
+    ("ALBUMARTISTS", Field::AlbumArtists),
+    ("GENRE", Field::Genre),
+    ("TRACKNUMBER", Field::TrackNumber),
+    ("DISCNUMBER", Field::DiscNumber),
+    ("ISRC", Field::Isrc),
+    ("MUSICBRAINZ_TRACKID", Field::RecordingMbid),
+    ("MUSICBRAINZ_ALBUMID", Field::ReleaseMbid),
+    ("MUSICBRAINZ_ARTISTID", Field::ArtistMbids),
+    ("MUSICBRAINZ_RELEASEGROUPID", Field::ReleaseGroupMbid),
+    ("MUSICBRAINZ_RELEASETRACKID", Field::TrackMbid),
+    ("MUSICBRAINZ_WORKID", Field::WorkMbids),
+    // Matroska
+    ("PART_NUMBER", Field::TrackNumber),
+    // ffmpeg's generic keys, which mpv shows for MP4 atoms and ID3 frames, and which APEv2
+    // spells `Track` and `Disc`
+    ("track", Field::TrackNumber),
+    ("disc", Field::DiscNumber),
+    // ID3 frames, and the UFID owner that holds the Recording MBID
+    ("TIT2", Field::Title),
+    ("TPE1", Field::Artist),
+    ("TALB", Field::Album),
+    ("TPE2", Field::AlbumArtist),
+    ("TCON", Field::Genre),
+    ("TRCK", Field::TrackNumber),
+    ("TPOS", Field::DiscNumber),
+    ("TSRC", Field::Isrc),
+    ("http://musicbrainz.org", Field::RecordingMbid),
+];
+
+/// What some tools put before the name of an iTunes freeform item
+const ITUNES_PREFIX: &str = "----:com.apple.iTunes:";
+
+#[derive(Debug)]
+pub struct Aliases(HashMap<String, Field>);
+
+impl Default for Aliases {
+    fn default() -> Self {
+        Self(
+            ALIASES
+                .iter()
+                .map(|&(key, field)| (field_name(key), field))
+                .collect(),
+        )
+    }
+}
+
+impl Aliases {
+    pub fn field(&self, key: &str) -> Option<Field> {
+        let key = key.strip_prefix(ITUNES_PREFIX).unwrap_or(key);
+        self.0.get(&field_name(key)).copied()
+    }
+
+    /// Takes a `key|field` pair from `listenbrainz-tag-aliases`
+    pub fn add(&mut self, alias: &str) -> Result<(), String> {
+        let Some((key, field)) = alias.split_once('|') else {
+            return Err(format!("Expected key|field, found {:?}", alias));
+        };
+        let Some(field) = Field::parse(field.trim()) else {
+            return Err(format!("Unknown field {:?}", field.trim()));
+        };
+        self.0.insert(field_name(key.trim()), field);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_duplicates() {
+        let mut keys: Vec<String> = ALIASES.iter().map(|(key, _)| field_name(key)).collect();
+        keys.sort();
+        let len = keys.len();
+        keys.dedup();
+        assert_eq!(keys.len(), len);
+    }
+
+    #[test]
+    fn vorbis() {
+        let aliases = Aliases::default();
+        assert_eq!(aliases.field("TITLE"), Some(Field::Title));
+        assert_eq!(aliases.field("ARTISTS"), Some(Field::Artists));
+        assert_eq!(aliases.field("ALBUMARTIST"), Some(Field::AlbumArtist));
+        assert_eq!(aliases.field("TRACKNUMBER"), Some(Field::TrackNumber));
+        assert_eq!(
+            aliases.field("MUSICBRAINZ_TRACKID"),
+            Some(Field::RecordingMbid)
+        );
+        assert_eq!(
+            aliases.field("musicbrainz_trackid"),
+            Some(Field::RecordingMbid)
+        );
+        assert_eq!(
+            aliases.field("MUSICBRAINZ_RELEASETRACKID"),
+            Some(Field::TrackMbid)
+        );
+        assert_eq!(
+            aliases.field("MUSICBRAINZ_RELEASEGROUPID"),
+            Some(Field::ReleaseGroupMbid)
+        );
+    }
+
+    /// Keys as `tags::read_id3` gives them: the common text frames renamed, the others as they
+    /// are, `TXXX` by description and `UFID` by owner
+    #[test]
+    fn id3() {
+        let aliases = Aliases::default();
+        assert_eq!(aliases.field("title"), Some(Field::Title));
+        assert_eq!(aliases.field("TPE2"), Some(Field::AlbumArtist));
+        assert_eq!(aliases.field("TRCK"), Some(Field::TrackNumber));
+        assert_eq!(aliases.field("TPOS"), Some(Field::DiscNumber));
+        assert_eq!(aliases.field("TSRC"), Some(Field::Isrc));
+        assert_eq!(
+            aliases.field("http://musicbrainz.org"),
+            Some(Field::RecordingMbid)
+        );
+        assert_eq!(
+            aliases.field("MusicBrainz Album Id"),
+            Some(Field::ReleaseMbid)
+        );
+        assert_eq!(
+            aliases.field("MusicBrainz Artist Id"),
+            Some(Field::ArtistMbids)
+        );
+        assert_eq!(
+            aliases.field("MusicBrainz Release Group Id"),
+            Some(Field::ReleaseGroupMbid)
+        );
+        assert_eq!(aliases.field("MusicBrainz Work Id"), Some(Field::WorkMbids));
+        // mpv's own names for ID3 frames
+        assert_eq!(aliases.field("album_artist"), Some(Field::AlbumArtist));
+        assert_eq!(aliases.field("TIT2"), Some(Field::Title));
+    }
+
+    /// Keys as `tags::read_mp4` and mpv give them, and freeform items with their full name
+    #[test]
+    fn mp4() {
+        let aliases = Aliases::default();
+        assert_eq!(aliases.field("title"), Some(Field::Title));
+        assert_eq!(aliases.field("album_artist"), Some(Field::AlbumArtist));
+        assert_eq!(aliases.field("track"), Some(Field::TrackNumber));
+        assert_eq!(aliases.field("disc"), Some(Field::DiscNumber));
+        assert_eq!(
+            aliases.field("MusicBrainz Track Id"),
+            Some(Field::RecordingMbid)
+        );
+        assert_eq!(
+            aliases.field("----:com.apple.iTunes:MusicBrainz Track Id"),
+            Some(Field::RecordingMbid)
+        );
+        assert_eq!(
+            aliases.field("----:com.apple.iTunes:MusicBrainz Release Track Id"),
+            Some(Field::TrackMbid)
+        );
+        assert_eq!(
+            aliases.field("----:com.apple.iTunes:MusicBrainz Release Group Id"),
+            Some(Field::ReleaseGroupMbid)
+        );
+    }
+
+    /// Keys as Picard writes them to APEv2 tags
+    #[test]
+    fn ape() {
+        let aliases = Aliases::default();
+        assert_eq!(aliases.field("Album"), Some(Field::Album));
+        assert_eq!(aliases.field("Album Artist"), Some(Field::AlbumArtist));
+        assert_eq!(aliases.field("Track"), Some(Field::TrackNumber));
+        assert_eq!(aliases.field("Disc"), Some(Field::DiscNumber));
+        assert_eq!(aliases.field("Genre"), Some(Field::Genre));
+        assert_eq!(
+            aliases.field("MUSICBRAINZ_TRACKID"),
+            Some(Field::RecordingMbid)
+        );
+    }
+    #[test]
+    fn matroska() {
+        let aliases = Aliases::default();
+        assert_eq!(aliases.field("ARTIST"), Some(Field::Artist));
+        assert_eq!(aliases.field("PART_NUMBER"), Some(Field::TrackNumber));
+        assert_eq!(aliases.field("ISRC"), Some(Field::Isrc));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let aliases = Aliases::default();
+        assert_eq!(aliases.field("COMMENT"), None);
+        assert_eq!(aliases.field("PERFORMER"), None);
+    }
+
+    #[test]
+    fn custom_aliases() {
+        let mut aliases = Aliases::default();
+        aliases.add("Band | artist").unwrap();
+        aliases.add("MB_RECORDING|recording_mbid").unwrap();
+        assert_eq!(aliases.field("BAND"), Some(Field::Artist));
+        assert_eq!(aliases.field("mb recording"), Some(Field::RecordingMbid));
+        assert!(aliases.add("Band").is_err());
+        assert!(aliases.add("Band|composer").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! A small expression language for skipping tracks by their metadata, e.g.
+//! `genre ~ "podcast|audiobook" or albumartist = "Various Artists"`
+//!
+//! A comparison is a field, an operator and a value: `=` and `!=` compare case-insensitively, `~`
+//! and `!~` match a case-insensitive regex. Comparisons combine with `and`, `or`, `not` and
+//! parentheses. Values with spaces or operators in them are quoted with `"` or `'`. Fields that
+//! aren't set are empty.
+
+use std::collections::HashMap;
+
+use regex::{Regex, RegexBuilder};
+
+#[derive(Debug)]
+pub enum Filter {
+    Or(Box<Filter>, Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Equals(String, String),
+    Matches(String, Regex),
+}
+
+/// Field names ignore case, spaces and underscores, so `albumartist` is also `ALBUM_ARTIST` and
+/// `Album Artist`
+pub fn field_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| *c != '_' && *c != ' ')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            pos: 0,
+        };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(filter),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+
+    /// Evaluates the filter against fields keyed by `field_name`
+    pub fn matches(&self, fields: &HashMap<String, String>) -> bool {
+        let field = |name: &str| fields.get(name).map_or("", String::as_str);
+        match self {
+            Self::Or(a, b) => a.matches(fields) || b.matches(fields),
+            Self::And(a, b) => a.matches(fields) && b.matches(fields),
+            Self::Not(filter) => !filter.matches(fields),
+            Self::Equals(name, value) => field(name).to_lowercase() == *value,
+            Self::Matches(name, regex) => regex.is_match(field(name)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Token {
+    Open,
+    Close,
+    Equals,
+    NotEquals,
+    Matches,
+    NotMatches,
+    Word(String),
+    Quoted(String),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '=' => tokens.push(Token::Equals),
+            '~' => tokens.push(Token::Matches),
+            '!' => match chars.next() {
+                Some('=') => tokens.push(Token::NotEquals),
+                Some('~') => tokens.push(Token::NotMatches),
+                _ => return Err("Expected != or !~".to_string()),
+            },
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next()),
+                        Some(q) if q == c => break,
+                        Some(c) => value.push(c),
+                        None => return Err(format!("Unterminated {}", c)),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            c => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()=!~\"'".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.keyword("or") {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.not()?;
+        while self.keyword("and") {
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        if self.keyword("not") {
+            Ok(Filter::Not(Box::new(self.not()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Filter, String> {
+        let field = match self.next() {
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                return match self.next() {
+                    Some(Token::Close) => Ok(filter),
+                    _ => Err("Expected )".to_string()),
+                };
+            }
+            Some(Token::Word(field)) => field_name(field),
+            token => return Err(format!("Expected a field, found {:?}", token)),
+        };
+        let (matches, negated) = match self.next() {
+            Some(Token::Equals) => (false, false),
+            Some(Token::NotEquals) => (false, true),
+            Some(Token::Matches) => (true, false),
+            Some(Token::NotMatches) => (true, true),
+            token => {
+                return Err(format!(
+                    "Expected an operator after {}, found {:?}",
+                    field, token
+                ))
+            }
+        };
+        let value = match self.next() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value,
+            token => return Err(format!("Expected a value for {}, found {:?}", field, token)),
+        };
+        let filter = if matches {
+            let regex = RegexBuilder::new(value)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| e.to_string())?;
+            Filter::Matches(field, regex)
+        } else {
+            Filter::Equals(field, value.to_lowercase())
+        };
+        Ok(if negated {
+            Filter::Not(Box::new(filter))
+        } else {
+            filter
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (field_name(name), value.to_string()))
+            .collect()
+    }
+
+    fn matches(expression: &str, pairs: &[(&str, &str)]) -> bool {
+        Filter::parse(expression).unwrap().matches(&fields(pairs))
+    }
+
+    #[test]
+    fn comparisons() {
+        let track = [("GENRE", "Podcast"), ("ALBUM_ARTIST", "Various Artists")];
+        assert!(matches("genre = podcast", &track));
+        assert!(!matches("genre != PODCAST", &track));
+        assert!(matches("genre ~ \"pod|audiobook\"", &track));
+        assert!(matches("genre !~ ^audio", &track));
+        assert!(matches("albumartist = 'various artists'", &track));
+        assert!(matches("Album_Artist ~ various", &track));
+        // Fields that aren't set are empty
+        assert!(matches("artist = ''", &track));
+        assert!(!matches("artist ~ .", &track));
+    }
+
+    #[test]
+    fn precedence() {
+        let track = [("artist", "A"), ("title", "T")];
+        // `and` binds tighter than `or`, and `not` tighter than both
+        assert!(matches("artist = x and title = x or artist = a", &track));
+        assert!(matches("artist = a or artist = x and title = x", &track));
+        assert!(!matches("(artist = a or artist = x) and title = x", &track));
+        assert!(!matches("not artist = a and title = t", &track));
+        assert!(matches("not (artist = x and title = t)", &track));
+        assert!(matches("not not artist = a", &track));
+        assert!(matches("artist = a AND title = t", &track));
+    }
+
+    #[test]
+    fn quoting() {
+        let track = [("title", "Don't Stop (Live) = \"Best\"")];
+        assert!(matches(r#"title = "don't stop (live) = \"best\"""#, &track));
+        assert!(matches(r#"title ~ 'don\'t stop .live.'"#, &track));
+        // Quotes take the backslash, so regex escapes need it doubled
+        assert!(matches(r#"title ~ "stop \\(live\\)""#, &track));
+        assert!(matches("title~stop", &track));
+        // Keywords are only keywords unquoted
+        let track = [("album", "or")];
+        assert!(matches("album = \"or\"", &track));
+    }
+
+    #[test]
+    fn errors() {
+        for expression in [
+            "",
+            "genre",
+            "genre =",
+            "genre ! podcast",
+            "genre = \"podcast",
+            "(genre = podcast",
+            "genre = podcast)",
+            "genre = podcast or",
+            "genre = podcast genre = audiobook",
+            "= podcast",
+            "genre ~ (",
+        ] {
+            assert!(Filter::parse(expression).is_err(), "{:?}", expression);
+        }
+    }
+}
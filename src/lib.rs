@@ -1,46 +1,394 @@
 use std::{
-    io::BufWriter,
+    collections::HashMap,
+    io,
     mem::ManuallyDrop,
     num::NonZeroU64,
     path::{Path, PathBuf},
     time::{Duration, Instant, SystemTime},
 };
 
+use aliases::{Aliases, Field};
 use calloop::{
     channel::{Channel, Sender},
-    timer::Timer,
-    EventLoop,
+    generic::Generic,
+    ping::Ping,
+    timer::{TimeoutAction, Timer},
+    EventLoop, Interest, LoopHandle, Mode, PostAction, RegistrationToken,
 };
-#[cfg(feature = "connman")]
+#[cfg(feature = "networkmanager")]
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+#[cfg(any(feature = "connman", feature = "networkmanager"))]
 use dbus::message::MatchRule;
-use id3::{Content, Tag};
 use libmpv::{
     events::{Event, PropertyData},
     Mpv, MpvStr,
 };
 use libmpv_sys::mpv_handle;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use template::Template;
 
+mod aliases;
 #[cfg(feature = "connman")]
 mod connman;
+mod filter;
+mod http;
+mod lookup;
+mod rules;
+mod tags;
+mod template;
 
 #[derive(Debug)]
 struct ListenbrainzData {
     payload: Payload,
+    /// Whether the current track can be scrobbled at all
+    scrobblable: bool,
+    /// Whether the current pass through the track is yet to be scrobbled
     scrobble: bool,
     token: String,
+    http: http::Client<Completion>,
+    imports_in_flight: usize,
     cache_path: PathBuf,
     online: bool,
-    scrobble_deadline: Instant,
-    pause_instant: Instant,
+    /// Set by the user to cache everything regardless of the network
+    offline: bool,
+    /// Set by the user to submit nothing at all, not even to the cache
+    private: bool,
+    /// Where the private session is remembered across restarts, if it is
+    private_path: Option<PathBuf>,
+    metered: bool,
+    metered_policy: MeteredPolicy,
+    /// Cache every listen and only submit the cache periodically, to spare the radio
+    batch: bool,
+    batch_interval: Duration,
+    batch_size: usize,
+    paused: bool,
+    /// When `playing_now` was last sent for the current track
+    playing_now_at: Option<Instant>,
+    /// How often `playing_now` is sent again while a track keeps playing
+    playing_now_interval: Duration,
+    play_time: PlayTime,
+    /// Seconds of the current track that have to be played before it is scrobbled
+    threshold: f64,
+    thresholds: Thresholds,
+    /// What the metadata of the current file has to include
+    requirement: Requirement,
+    path_rules: rules::PathRules,
+    /// Whether the path rules, the video policy or a lack of audio keep the current file from
+    /// being scrobbled
+    excluded: bool,
+    video_policy: VideoPolicy,
+    chapter_policy: ChapterPolicy,
+    /// Tracks matching any of these are never scrobbled
+    skip: Vec<filter::Filter>,
+    /// Every tag of the current file, keyed by `filter::field_name`
+    tags: Vec<(String, String)>,
+    timer: Option<RegistrationToken>,
+    /// Resolves the API's addresses again, after connecting to them failed
+    resolve_timer: Option<RegistrationToken>,
+    /// Points the scrobble timer anew from outside the event handlers, e.g. once a lookup made
+    /// the track scrobblable
+    reschedule: Ping,
+    /// Where playback of the current file started
+    start_pos: Option<f64>,
+    /// Where playback was before the seeks leading up to the next playback restart
+    seek_from: Option<f64>,
+    path: String,
+    /// Whether the current file has been loaded since it was started
+    loaded: bool,
+    /// The pass through the current file mpv quit in the middle of last time, until playback starts
+    resume: Option<Session>,
+    /// Whether the current file is a stream without a duration, where every ICY title is a track
+    stream: bool,
+    /// The stream's `icy-name` and URL, lowercased, for matching `icy_rules`
+    station: String,
+    icy_title: String,
+    /// Templates for the ICY titles of stations containing the first string
+    icy_rules: Vec<(String, Template)>,
+    /// Templates for the ICY titles of any station
+    icy_templates: Vec<Template>,
+    /// Seconds of a stream track that have to be played before it is scrobbled
+    stream_threshold: f64,
+    /// Fill in missing tags from the playlist, the path and the file name
+    infer: bool,
+    /// Templates for the last directories and the file name, with how many path components they
+    /// cover
+    path_templates: Vec<(usize, Template)>,
+    /// Which tag keys mean which field
+    aliases: Aliases,
+    /// Look MBIDs up for files tagged with only an artist and a title
+    lookup: bool,
+    /// How closely a lookup must match the artist and title to be taken, from 0 to 1
+    lookup_confidence: f64,
+    lookups: lookup::Cache,
+    /// The key of the current track's lookup, while it is in flight
+    lookup_pending: Option<String>,
+    /// Start times of the chapters of a file that is scrobbled chapter by chapter
+    chapters: Vec<f64>,
+    /// The current chapter, or -1 before the first one
+    chapter: i64,
+    /// Metadata of the whole file, which every chapter is a track of
+    album: TrackMetadata,
+    duration: f64,
+}
+
+/// Media time of the current track that has actually been played, as opposed to skipped over
+#[derive(Debug)]
+struct PlayTime {
+    played: f64,
+    /// When and at which position playback was last accounted for, while playing
+    since: Option<(Instant, f64)>,
+    /// The position playback was last accounted for at
+    pos: f64,
+    speed: f64,
+    /// When playback of this pass through the track actually began, which is when it is listened
+    /// at
+    started: Option<SystemTime>,
+}
+
+impl Default for PlayTime {
+    fn default() -> Self {
+        Self {
+            played: 0.0,
+            since: None,
+            pos: 0.0,
+            speed: 1.0,
+            started: None,
+        }
+    }
+}
+
+impl PlayTime {
+    /// Accounts for the time played since the last update. After a seek, `pos` says nothing about
+    /// how much was played, so the time elapsed is used instead.
+    fn update(&mut self, playing: bool, pos: f64, seeked: bool) {
+        let now = Instant::now();
+        if let Some((since, since_pos)) = self.since {
+            let elapsed = now.duration_since(since).as_secs_f64() * self.speed;
+            self.played += if seeked {
+                elapsed
+            } else {
+                // Allow for some jitter, but don't count jumps mpv didn't report as a seek
+                (pos - since_pos).clamp(0.0, elapsed + 1.0)
+            };
+        }
+        self.since = playing.then_some((now, pos));
+        self.pos = pos;
+        if playing && self.started.is_none() {
+            self.started = Some(SystemTime::now());
+        }
+    }
+
+    /// Starts counting a new pass through the track from here
+    fn restart(&mut self) {
+        self.played = 0.0;
+        self.started = self.since.map(|_| SystemTime::now());
+    }
+
+    /// Estimated position in the track
+    fn position(&self) -> f64 {
+        match self.since {
+            Some((since, pos)) => pos + since.elapsed().as_secs_f64() * self.speed,
+            None => self.pos,
+        }
+    }
+
+    /// Seconds played so far, including the time since the last update
+    fn played(&self) -> f64 {
+        self.played
+            + self
+                .since
+                .map_or(0.0, |(since, _)| since.elapsed().as_secs_f64() * self.speed)
+    }
+
+    /// When `threshold` seconds will have been played, if playing
+    fn reaches(&self, threshold: f64) -> Option<Instant> {
+        let (since, _) = self.since?;
+        let remaining = (threshold - self.played).max(0.0) / self.speed;
+        since.checked_add(Duration::try_from_secs_f64(remaining).ok()?)
+    }
 }
 
-impl Default for ListenbrainzData {
+/// How much of a track has to be played, depending on its length
+#[derive(Debug)]
+struct Thresholds {
+    /// Share of the track, in percent
+    percent: f64,
+    /// Seconds after which any track counts, however long
+    max: f64,
+    /// Tracks shorter than this many seconds are never scrobbled
+    min_length: f64,
+    /// Tracks longer than this many seconds, like audiobooks or DJ mixes, follow `long` instead
+    max_length: Option<f64>,
+    /// Seconds of an overly long track that have to be played, which is ignored without it
+    long: Option<f64>,
+}
+
+impl Default for Thresholds {
     fn default() -> Self {
+        Self {
+            percent: 50.0,
+            max: 240.0,
+            min_length: 30.0,
+            max_length: None,
+            long: None,
+        }
+    }
+}
+
+impl Thresholds {
+    /// Whether a track of `length` seconds is too long to be a song, like an audiobook or a DJ mix
+    fn is_long(&self, length: f64) -> bool {
+        self.max_length
+            .is_some_and(|max_length| length > max_length)
+    }
+
+    /// Seconds of a track of `length` seconds that have to be played, if it is scrobbled at all
+    fn threshold(&self, length: f64) -> Option<f64> {
+        if length < self.min_length {
+            return None;
+        }
+        if self.is_long(length) {
+            return self.long.map(|long| long.min(length));
+        }
+        Some(f64::min(self.max, length * self.percent / 100.0))
+    }
+}
+
+/// A pass through a track that mpv quit in the middle of, to be picked up when it is resumed
+#[derive(Serialize, Deserialize, Debug)]
+struct Session {
+    path: String,
+    chapter: i64,
+    artist_name: String,
+    track_name: String,
+    position: f64,
+    played: f64,
+    /// When the pass began, in seconds since the epoch
+    started: u64,
+}
+
+/// What the metadata of a track has to include for it to be scrobbled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Requirement {
+    /// Only the artist and title ListenBrainz needs, even if the title is just the file name
+    Nothing,
+    /// A tagged artist and title
+    ArtistTitle,
+    /// A tagged artist, title and album
+    Release,
+    /// A tagged artist, title and album, and the MBID of the release
+    ReleaseMbid,
+    /// A tagged artist and title, and the MBID of the recording
+    RecordingMbid,
+}
+
+impl Requirement {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "nothing" => Some(Self::Nothing),
+            "artist-title" => Some(Self::ArtistTitle),
+            "release" => Some(Self::Release),
+            "release-mbid" => Some(Self::ReleaseMbid),
+            "mbid" => Some(Self::RecordingMbid),
+            _ => {
+                eprintln!("Unknown scrobble requirement {:?}", value);
+                None
+            }
+        }
+    }
+
+    fn satisfied_by(self, metadata: &TrackMetadata) -> bool {
+        !metadata.artist_name.is_empty()
+            && !metadata.track_name.is_empty()
+            && match self {
+                Self::Nothing | Self::ArtistTitle => true,
+                Self::Release => !metadata.release_name.is_empty(),
+                Self::ReleaseMbid => {
+                    !metadata.release_name.is_empty()
+                        && !metadata.additional_info.release_mbid.is_empty()
+                }
+                Self::RecordingMbid => !metadata.additional_info.recording_mbid.is_empty(),
+            }
+    }
+}
+
+/// Which files with a real video track are scrobbled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoPolicy {
+    /// Treat videos like audio files
+    Always,
+    /// Only music videos tagged with the recording's MBID
+    Mbid,
+    Never,
+}
+
+/// Which files with chapters are scrobbled chapter by chapter rather than as a whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChapterPolicy {
+    Always,
+    /// Only files where every chapter has a title, like CUE sheets
+    Titled,
+    Never,
+}
+
+/// What to do with listens while the active connection is metered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MeteredPolicy {
+    /// Cache everything until an unmetered connection appears
+    Cache,
+    /// Send single listens, but keep the cache until an unmetered connection appears
+    Single,
+    /// Treat metered connections like any other
+    Ignore,
+}
+
+impl ListenbrainzData {
+    fn connected(&self) -> bool {
+        self.online && !self.offline
+    }
+
+    /// Whether single listens and `playing_now` may be sent right now
+    fn may_send(&self) -> bool {
+        self.connected() && !(self.metered && self.metered_policy == MeteredPolicy::Cache)
+    }
+
+    /// Whether the cache may be imported right now
+    fn may_flush(&self) -> bool {
+        self.connected()
+            && !self.private
+            && !(self.metered && self.metered_policy != MeteredPolicy::Ignore)
+    }
+}
+
+impl ListenbrainzData {
+    /// The fields filters are evaluated against: the tags of the file, with the artist, title and
+    /// album of the current track
+    fn filter_fields(&self) -> HashMap<String, String> {
+        let mut fields: HashMap<String, String> = self.tags.iter().cloned().collect();
+        let metadata = &self.payload.track_metadata;
+        fields.insert("artist".to_string(), metadata.artist_name.clone());
+        fields.insert("title".to_string(), metadata.track_name.clone());
+        fields.insert("album".to_string(), metadata.release_name.clone());
+        fields
+    }
+
+    /// Whether a skip filter matches the current track
+    fn skipped(&self) -> bool {
+        if self.skip.is_empty() {
+            return false;
+        }
+        let fields = self.filter_fields();
+        self.skip.iter().any(|filter| filter.matches(&fields))
+    }
+
+    fn new(ping: Ping, reschedule: Ping) -> Self {
         Self {
             payload: Payload::default(),
+            scrobblable: false,
             scrobble: false,
             token: String::new(),
+            http: http::Client::new(ping),
+            imports_in_flight: 0,
             cache_path: {
                 #[cfg(target_os = "linux")]
                 {
@@ -52,8 +400,52 @@ impl Default for ListenbrainzData {
                 }
             },
             online: false,
-            scrobble_deadline: Instant::now(),
-            pause_instant: Instant::now(),
+            offline: false,
+            private: false,
+            private_path: None,
+            metered: false,
+            metered_policy: MeteredPolicy::Single,
+            batch: false,
+            batch_interval: Duration::from_secs(3600),
+            batch_size: 25,
+            paused: false,
+            playing_now_at: None,
+            playing_now_interval: Duration::from_secs(300),
+            play_time: PlayTime::default(),
+            threshold: 0.0,
+            thresholds: Thresholds::default(),
+            requirement: Requirement::RecordingMbid,
+            path_rules: rules::PathRules::default(),
+            excluded: false,
+            video_policy: VideoPolicy::Mbid,
+            chapter_policy: ChapterPolicy::Titled,
+            skip: Vec::new(),
+            tags: Vec::new(),
+            timer: None,
+            resolve_timer: None,
+            reschedule,
+            start_pos: None,
+            seek_from: None,
+            path: String::new(),
+            loaded: false,
+            resume: None,
+            stream: false,
+            station: String::new(),
+            icy_title: String::new(),
+            icy_rules: Vec::new(),
+            icy_templates: icy_templates(&[" - ", " – ", " — "]),
+            stream_threshold: 60.0,
+            infer: false,
+            path_templates: Vec::new(),
+            aliases: Aliases::default(),
+            lookup: false,
+            lookup_confidence: 0.8,
+            lookups: lookup::Cache::default(),
+            lookup_pending: None,
+            chapters: Vec::new(),
+            chapter: -1,
+            album: TrackMetadata::default(),
+            duration: 0.0,
         }
     }
 }
@@ -71,7 +463,7 @@ struct Payload {
     track_metadata: TrackMetadata,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, Clone)]
 struct TrackMetadata {
     additional_info: AdditionalInfo,
     artist_name: String,
@@ -79,7 +471,7 @@ struct TrackMetadata {
     release_name: String,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 struct AdditionalInfo {
     media_player: &'static str,
     submission_client: &'static str,
@@ -88,9 +480,36 @@ struct AdditionalInfo {
     release_mbid: String,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     artist_mbids: Vec<String>,
+    /// The credited artists, one for each of `artist_mbids`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    artist_names: Vec<String>,
     #[serde(skip_serializing_if = "String::is_empty")]
     recording_mbid: String,
-    duration_ms: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    release_group_mbid: String,
+    /// The MBID of the track on the release, as opposed to the recording
+    #[serde(skip_serializing_if = "String::is_empty")]
+    track_mbid: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    work_mbids: Vec<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    isrc: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    tracknumber: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    discnumber: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    release_artist_name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    release_artist_names: Vec<String>,
+    /// Genres, which ListenBrainz takes as tags
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// Whether the artist, title or album were guessed rather than read from tags
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    metadata_inferred: bool,
 }
 
 #[derive(Serialize, Default, Debug)]
@@ -107,146 +526,891 @@ impl Default for AdditionalInfo {
             submission_client_version: env!("CARGO_PKG_VERSION"),
             release_mbid: String::new(),
             artist_mbids: Vec::new(),
+            artist_names: Vec::new(),
             recording_mbid: String::new(),
-            duration_ms: 0,
+            release_group_mbid: String::new(),
+            track_mbid: String::new(),
+            work_mbids: Vec::new(),
+            isrc: String::new(),
+            tracknumber: String::new(),
+            discnumber: String::new(),
+            release_artist_name: String::new(),
+            release_artist_names: Vec::new(),
+            tags: Vec::new(),
+            duration_ms: None,
+            metadata_inferred: false,
         }
     }
 }
 
-fn scrobble(
-    listen_type: &'static str,
-    payload: &Payload,
-    online: bool,
-    token: &str,
-    cache_path: &Path,
-) {
+/// What to do once a request to ListenBrainz is done
+#[derive(Debug)]
+enum Completion {
+    PlayingNow,
+    /// A single listen, cached under its timestamp if it can't be submitted
+    Listen(NonZeroU64, Vec<u8>),
+    /// An import of these cached listens, which are removed once submitted
+    Import(Vec<PathBuf>),
+    Feedback,
+    /// A lookup of MBIDs, by its key in the lookup cache
+    Lookup(String),
+}
+
+fn scrobble(listen_type: &'static str, data: &mut ListenbrainzData) {
+    if data.private {
+        return;
+    }
     let send = ListenbrainzSingleListen {
         listen_type,
-        payload: [payload],
+        payload: [&data.payload],
     };
     #[cfg(debug_assertions)]
     eprintln!("{}", serde_json::to_string_pretty(&send).unwrap());
-    if online {
-        let status = ureq::post("https://api.listenbrainz.org/1/submit-listens")
-            .set("Authorization", token)
-            .send_json(send);
-        if status.is_ok() {
-            import_cache(token, cache_path);
-            return;
+    let completion = match data.payload.listened_at {
+        Some(listened_at) => {
+            Completion::Listen(listened_at, serde_json::to_vec(&data.payload).unwrap())
+        }
+        None => Completion::PlayingNow,
+    };
+    if data.batch {
+        // `playing_now` would be stale by the time a batch is sent
+        if let Completion::Listen(listened_at, payload) = completion {
+            cache_listen(&data.cache_path, listened_at, &payload);
+            let cached = std::fs::read_dir(&data.cache_path).unwrap().count();
+            if cached >= data.batch_size && data.may_flush() {
+                import_cache(data);
+            }
+        }
+    } else if data.may_send() {
+        let body = serde_json::to_vec(&send).unwrap();
+        data.http
+            .enqueue(http::Request::post("/1/submit-listens", body), completion);
+    } else if let Completion::Listen(listened_at, payload) = completion {
+        cache_listen(&data.cache_path, listened_at, &payload);
+    }
+}
+
+fn cache_listen(cache_path: &Path, listened_at: NonZeroU64, payload: &[u8]) {
+    std::fs::write(cache_path.join(format!("{}.json", listened_at)), payload).unwrap();
+}
+
+fn complete(
+    data: &mut ListenbrainzData,
+    completion: Completion,
+    result: io::Result<http::Response>,
+) {
+    let result = result.and_then(http::Response::error_for_status);
+    match completion {
+        Completion::PlayingNow | Completion::Listen(..) if result.is_ok() => {
+            if data.may_flush() {
+                import_cache(data);
+            }
+        }
+        Completion::PlayingNow => {
+            eprintln!("Error submitting playing_now: {}", result.unwrap_err());
+        }
+        Completion::Listen(listened_at, payload) => {
+            eprintln!("Error submitting listen: {}", result.unwrap_err());
+            cache_listen(&data.cache_path, listened_at, &payload);
+        }
+        Completion::Import(files) => {
+            data.imports_in_flight -= 1;
+            match result {
+                Ok(_) => files.iter().try_for_each(std::fs::remove_file).unwrap(),
+                Err(e) => eprintln!("Error importing {}", e),
+            }
+        }
+        Completion::Feedback => match result {
+            Ok(_) => eprintln!("Feedback submitted successfully"),
+            Err(e) => eprintln!("Error submitting feedback: {}", e),
+        },
+        Completion::Lookup(key) => {
+            let current = data.lookup_pending.as_ref() == Some(&key);
+            if current {
+                data.lookup_pending = None;
+            }
+            let lookup = match result {
+                Ok(response) => lookup::Lookup::parse(&response.body),
+                Err(e) => {
+                    eprintln!("Error looking up MBIDs: {}", e);
+                    return;
+                }
+            };
+            data.lookups.insert(key, lookup.clone());
+            // The track may have become scrobblable, now that it has MBIDs
+            if current
+                && apply_lookup(data, lookup.as_ref())
+                && !data.scrobblable
+                && data.requirement.satisfied_by(&data.payload.track_metadata)
+            {
+                data.scrobblable = true;
+                data.scrobble = true;
+                data.reschedule.ping();
+                playing_now(data);
+            }
+        }
+    }
+}
+
+/// Fills in the MBIDs of a track tagged with only an artist and a title from the lookup cache, or
+/// looks them up, to be filled in once the answer arrives
+fn look_up(data: &mut ListenbrainzData) {
+    let metadata = &data.payload.track_metadata;
+    if !data.lookup
+        || data.stream
+        || !metadata.additional_info.recording_mbid.is_empty()
+        || metadata.artist_name.is_empty()
+        || metadata.track_name.is_empty()
+    {
+        return;
+    }
+    let key = lookup::key(
+        &metadata.artist_name,
+        &metadata.track_name,
+        &metadata.release_name,
+    );
+    match data.lookups.get(&key) {
+        Some(lookup) => {
+            let lookup = lookup.clone();
+            apply_lookup(data, lookup.as_ref());
+        }
+        // Looking a track up tells ListenBrainz what is playing
+        None if data.may_send() && !data.private => {
+            let request = lookup::request(
+                &metadata.artist_name,
+                &metadata.track_name,
+                &metadata.release_name,
+            );
+            data.http.enqueue(request, Completion::Lookup(key.clone()));
+            data.lookup_pending = Some(key);
+        }
+        None => {}
+    }
+}
+
+/// Takes the MBIDs of a lookup into the current track, if it matches the track closely enough.
+/// MBIDs the track is already tagged with are kept.
+fn apply_lookup(data: &mut ListenbrainzData, lookup: Option<&lookup::Lookup>) -> bool {
+    let metadata = &mut data.payload.track_metadata;
+    let Some(lookup) = lookup.filter(|lookup| {
+        lookup.confidence(&metadata.artist_name, &metadata.track_name) >= data.lookup_confidence
+    }) else {
+        return false;
+    };
+    let info = &mut metadata.additional_info;
+    info.recording_mbid = lookup.recording_mbid.clone();
+    if info.release_mbid.is_empty() {
+        info.release_mbid = lookup.release_mbid.clone();
+    }
+    if info.artist_mbids.is_empty() {
+        info.artist_mbids = lookup.artist_mbids.clone();
+        credit_artists(metadata);
+    }
+    true
+}
+
+/// How long after connecting failed the API's addresses are resolved again
+const RESOLVE_DELAY: Duration = Duration::from_secs(10);
+
+/// Starts queued requests until one of them has to wait on the network, registering its socket
+/// and timeout with the event loop
+fn dispatch(data: &mut ListenbrainzData, handle: &LoopHandle<'static, ListenbrainzData>) {
+    loop {
+        let fd = match data.http.start() {
+            http::Started::Idle => break,
+            http::Started::Done(completion, result) => {
+                complete(data, completion, result);
+                continue;
+            }
+            http::Started::InFlight(fd) => fd,
+        };
+
+        let io_handle = handle.clone();
+        let io = handle
+            .insert_source(
+                Generic::new(http::Fd(fd), Interest::BOTH, Mode::Edge),
+                move |_readiness, _fd, data| {
+                    let done = match data.http.progress() {
+                        http::Progress::Pending => return Ok(PostAction::Continue),
+                        http::Progress::Done(completion, result) => Some((completion, result)),
+                        http::Progress::Retry => None,
+                    };
+                    if let Some((_, timeout)) = data.http.registration.take() {
+                        io_handle.remove(timeout);
+                    }
+                    if let Some((completion, result)) = done {
+                        complete(data, completion, result);
+                    }
+                    data.http.wake();
+                    Ok(PostAction::Remove)
+                },
+            )
+            .unwrap();
+        let timeout_handle = handle.clone();
+        let timeout = handle
+            .insert_source(
+                Timer::from_deadline(data.http.deadline().unwrap()),
+                move |_event, _metadata, data| match data.http.deadline() {
+                    Some(deadline) if deadline > Instant::now() => {
+                        TimeoutAction::ToInstant(deadline)
+                    }
+                    _ => {
+                        if let Some((io, _)) = data.http.registration.take() {
+                            timeout_handle.remove(io);
+                        }
+                        if let Some(completion) = data.http.abort() {
+                            complete(
+                                data,
+                                completion,
+                                Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
+                            );
+                        }
+                        data.http.wake();
+                        TimeoutAction::Drop
+                    }
+                },
+            )
+            .unwrap();
+        data.http.registration = Some((io, timeout));
+        break;
+    }
+
+    // Resolving blocks the event loop, so it isn't retried on every request while the network is
+    // down
+    if data.http.is_stale() && data.resolve_timer.is_none() {
+        data.resolve_timer = Some(
+            handle
+                .insert_source(
+                    Timer::from_duration(RESOLVE_DELAY),
+                    |_event, _metadata, data| {
+                        data.resolve_timer = None;
+                        data.http.resolve();
+                        data.http.wake();
+                        TimeoutAction::Drop
+                    },
+                )
+                .unwrap(),
+        );
+    }
+}
+
+/// Applies a change of connectivity, flushing the cache and resending `playing_now` once the
+/// connection allows it
+fn update_connectivity(data: &mut ListenbrainzData, update: impl FnOnce(&mut ListenbrainzData)) {
+    let could_send = data.may_send();
+    let could_flush = data.may_flush();
+    update(data);
+    if data.may_flush() && !could_flush {
+        import_cache(data);
+    }
+    if data.may_send() && !could_send {
+        playing_now(data);
+    }
+}
+
+/// Tells ListenBrainz what is playing, unless it is paused
+fn playing_now(data: &mut ListenbrainzData) {
+    if !data.scrobblable || data.paused || !data.may_send() {
+        return;
+    }
+    data.payload.listened_at = None;
+    scrobble("playing_now", data);
+    data.playing_now_at = Some(Instant::now());
+}
+
+#[cfg(any(feature = "connman", feature = "networkmanager"))]
+fn network_changed(data: &mut ListenbrainzData, online: bool, metered: bool) {
+    update_connectivity(data, |data| {
+        data.online = online;
+        data.metered = metered;
+    });
+}
+
+fn set_offline(mpv: &Mpv, data: &mut ListenbrainzData, offline: bool) {
+    update_connectivity(data, |data| data.offline = offline);
+    show_text(
+        mpv,
+        if offline {
+            "ListenBrainz: offline, listens are cached"
+        } else if !data.online {
+            "ListenBrainz: online, but there is no network connection"
+        } else {
+            "ListenBrainz: online"
+        },
+    );
+}
+
+fn set_private(mpv: &Mpv, data: &mut ListenbrainzData, private: bool) {
+    let could_flush = data.may_flush();
+    data.private = private;
+    if let Some(private_path) = &data.private_path {
+        let result = if private {
+            std::fs::write(private_path, b"")
+        } else {
+            std::fs::remove_file(private_path).or_else(|e| match e.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+        };
+        if let Err(e) = result {
+            eprintln!("Error remembering the private session: {}", e);
         }
     }
-    if let Some(listened_at) = payload.listened_at {
-        serde_json::to_writer(
-            BufWriter::new(
-                std::fs::File::create(cache_path.join(format!("{}.json", listened_at))).unwrap(),
-            ),
-            &payload,
+    if data.may_flush() && !could_flush {
+        import_cache(data);
+    }
+    show_text(
+        mpv,
+        if private {
+            "ListenBrainz: private session, nothing is submitted"
+        } else {
+            "ListenBrainz: private session ended"
+        },
+    );
+}
+
+/// Files that end with an error arrive as one of these errors rather than `EndFile`
+fn ends_file(e: &libmpv::Error) -> bool {
+    use libmpv::mpv_error;
+    matches!(
+        e,
+        libmpv::Error::Raw(
+            mpv_error::LoadingFailed
+                | mpv_error::AoInitFailed
+                | mpv_error::VoInitFailed
+                | mpv_error::NothingToPlay
+                | mpv_error::UnknownFormat
+                | mpv_error::Unsupported
+                | mpv_error::Generic
         )
-        .unwrap();
+    )
+}
+
+fn show_text(mpv: &Mpv, text: &str) {
+    let text = format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    if let Err(e) = mpv.command("show-text", &[&text]) {
+        eprintln!("Error showing {}: {:?}", text, e);
+    }
+}
+
+/// connman has no notion of metered connections, so mobile data and phones tethered over bluetooth
+/// are treated as metered
+#[cfg(feature = "connman")]
+fn connman_service_metered(properties: &dbus::arg::PropMap) -> bool {
+    matches!(
+        properties.get("Type").and_then(|t| t.0.as_str()),
+        Some("cellular" | "bluetooth")
+    )
+}
+
+/// Whether a service is the one that is connected, if its state is among `properties`
+#[cfg(feature = "connman")]
+fn connman_service_connected(properties: &dbus::arg::PropMap) -> Option<bool> {
+    let state = properties.get("State")?.0.as_str()?;
+    Some(state == "ready" || state == "online")
+}
+
+/// Whether the connected service is metered. connman orders services by preference, so it is the
+/// first one that is connected, whether or not other services come up as well.
+#[cfg(feature = "connman")]
+fn connman_metered<'a>(
+    order: impl Iterator<Item = &'a dbus::Path<'static>>,
+    services: &HashMap<dbus::Path<'static>, (bool, bool)>,
+) -> bool {
+    order
+        .filter_map(|path| services.get(path))
+        .find(|(connected, _)| *connected)
+        .is_some_and(|&(_, metered)| metered)
+}
+
+/// `NM_STATE_CONNECTED_GLOBAL`
+#[cfg(feature = "networkmanager")]
+const NM_STATE_CONNECTED_GLOBAL: u32 = 70;
+
+/// `NM_METERED_YES` or `NM_METERED_GUESS_YES`
+#[cfg(feature = "networkmanager")]
+fn nm_metered(metered: u32) -> bool {
+    metered == 1 || metered == 3
+}
+
+/// No option needs more than a year, and longer durations could overflow the timers' deadlines
+const MAX_SECONDS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+fn parse_seconds(key: &str, value: &str) -> Option<Duration> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds <= MAX_SECONDS => {
+            Duration::try_from_secs_f64(seconds).ok()
+        }
+        _ => {
+            eprintln!("Invalid number of seconds {:?} for {}", value, key);
+            None
+        }
+    }
+}
+
+/// `path` joined with mpv's working directory, so that files opened by a relative path match
+/// rules for absolute ones. URLs are left as they are.
+fn absolute_path(mpv: &Mpv, path: &str) -> PathBuf {
+    if path.contains("://") {
+        return PathBuf::from(path);
+    }
+    match mpv.get_property::<MpvStr>("working-directory") {
+        Ok(working_directory) => Path::new(&*working_directory).join(path),
+        Err(_) => PathBuf::from(path),
+    }
+}
+
+/// The requirement for the file at `path`, which is `listenbrainz-require` unless the file is
+/// inside one of the directories in `listenbrainz-require-dirs`. Both are read anew for every
+/// file, so that profiles can change them.
+fn requirement(mpv: &Mpv, path: &str) -> Requirement {
+    let mut requirement = Requirement::RecordingMbid;
+    let Ok(script_opts) = mpv.get_property::<libmpv::MpvNode>("script-opts") else {
+        return requirement;
+    };
+    let path = absolute_path(mpv, path);
+
+    // The deepest directory containing the file wins
+    let mut directory: Option<(usize, Requirement)> = None;
+    for (key, value) in script_opts.to_map().unwrap() {
+        match key {
+            "listenbrainz-require" => {
+                if let Some(value) = Requirement::parse(value.to_str().unwrap()) {
+                    requirement = value;
+                }
+            }
+            "listenbrainz-require-dirs" => {
+                for rule in value.to_str().unwrap().split(';') {
+                    let Some((dir, value)) = rule.rsplit_once('|') else {
+                        continue;
+                    };
+                    let dir = Path::new(dir.trim());
+                    let depth = dir.components().count();
+                    if path.starts_with(dir) && directory.map_or(true, |(d, _)| depth > d) {
+                        if let Some(value) = Requirement::parse(value.trim()) {
+                            directory = Some((depth, value));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    directory.map_or(requirement, |(_, requirement)| requirement)
+}
+
+fn add_skip_filter(data: &mut ListenbrainzData, expression: &str) {
+    match filter::Filter::parse(expression) {
+        Ok(filter) => data.skip.push(filter),
+        Err(e) => eprintln!("Invalid skip filter {:?}: {}", expression, e),
     }
 }
 
-fn import_cache(token: &str, cache_path: &Path) {
-    let mut read_dir = cache_path.read_dir().unwrap();
-    let is_occupied = read_dir.next().is_some();
-    let is_one_file = read_dir.next().is_none();
-    if cache_path.exists() && is_occupied {
-        let mut request = if is_one_file {
+/// Reads the path rules and skip filters from `script-opts/listenbrainz.conf`, one `include=`,
+/// `exclude=` or `skip=` per line
+fn read_config_file(path: &Path, data: &mut ListenbrainzData) {
+    let Ok(config) = std::fs::read_to_string(path) else {
+        return;
+    };
+    for line in config.lines().map(str::trim) {
+        match line.split_once('=') {
+            _ if line.starts_with('#') => {}
+            Some(("include", pattern)) => data.path_rules.include(pattern),
+            Some(("exclude", pattern)) => data.path_rules.exclude(pattern),
+            Some(("skip", expression)) => add_skip_filter(data, expression),
+            _ => {}
+        }
+    }
+}
+
+/// How far from where it was quit a resumed track may start for its session to be picked up
+const RESUME_WINDOW: f64 = 5.0;
+
+/// Kept next to the cache rather than in it, where it would be taken for a listen
+fn beside_cache(cache_path: &Path, extension: &str) -> PathBuf {
+    cache_path.with_extension(extension)
+}
+
+fn session_path(cache_path: &Path) -> PathBuf {
+    beside_cache(cache_path, "session")
+}
+
+/// Remembers the current pass through the track, which has yet to be scrobbled
+fn save_session(data: &ListenbrainzData, path: String, position: f64) {
+    let Some(started) = data.play_time.started else {
+        return;
+    };
+    let session = Session {
+        path,
+        chapter: data.chapter,
+        artist_name: data.payload.track_metadata.artist_name.clone(),
+        track_name: data.payload.track_metadata.track_name.clone(),
+        position,
+        played: data.play_time.played,
+        started: started
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+    if let Err(e) = std::fs::write(
+        session_path(&data.cache_path),
+        serde_json::to_vec(&session).unwrap(),
+    ) {
+        eprintln!("Error saving the listen in progress: {}", e);
+    }
+}
+
+/// Takes the session saved when mpv last quit, if it was in the middle of `path`
+fn load_session(cache_path: &Path, path: &str) -> Option<Session> {
+    let session_path = session_path(cache_path);
+    let session: Session = serde_json::from_slice(&std::fs::read(&session_path).ok()?).ok()?;
+    if session.path != path {
+        return None;
+    }
+    let _ = std::fs::remove_file(session_path);
+    Some(session)
+}
+
+/// How long mpv's shutdown may be held up to finish queued requests
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// ListenBrainz rejects imports of more listens than this
+const MAX_LISTENS_PER_REQUEST: usize = 1000;
+
+fn import_cache(data: &mut ListenbrainzData) {
+    if data.imports_in_flight > 0 {
+        return;
+    }
+    let files: Vec<PathBuf> = std::fs::read_dir(&data.cache_path)
+        .unwrap()
+        .map(|i| i.unwrap().path())
+        .collect();
+    for files in files.chunks(MAX_LISTENS_PER_REQUEST) {
+        let mut request = if files.len() == 1 {
             br#"{"listen_type":"single","payload":["#.to_vec()
         } else {
             br#"{"listen_type":"import","payload":["#.to_vec()
         };
-        for i in std::fs::read_dir(&cache_path).unwrap() {
-            let path = i.unwrap().path();
-            std::io::copy(
-                &mut std::fs::File::open(path.as_path()).unwrap(),
-                &mut request,
-            )
-            .unwrap();
+        for path in files {
+            std::io::copy(&mut std::fs::File::open(path).unwrap(), &mut request).unwrap();
             request.push(b',');
         }
         request.pop();
         request.extend_from_slice(b"]}");
         #[cfg(debug_assertions)]
         eprintln!("{}", unsafe { std::str::from_utf8_unchecked(&request) });
-        let status = ureq::post("https://api.listenbrainz.org/1/submit-listens")
-            .set("Authorization", token)
-            .set("Content-Type", "json")
-            .send_bytes(&request);
-        if status.is_err() {
-            eprintln!("Error importing {:?}", status);
-            return;
+        data.imports_in_flight += 1;
+        data.http.enqueue(
+            http::Request::post("/1/submit-listens", request),
+            Completion::Import(files.to_vec()),
+        );
+    }
+}
+
+/// Splits a list of MBIDs, separated by `;` or, failing that, `/`
+fn split_mbids(mbids: &str) -> Vec<String> {
+    let separator = if memchr::memchr(b';', mbids.as_bytes()).is_some() {
+        ";"
+    } else {
+        "/"
+    };
+    mbids
+        .split(separator)
+        .map(|f| f.trim().to_string())
+        .collect()
+}
+
+/// Splits a multi-value tag, whose values come joined with `;`
+fn split_values(values: &str) -> Vec<String> {
+    values
+        .split(';')
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Phrases that join artists in a credit, like `A feat. B`
+const JOIN_PHRASES: [&str; 9] = [
+    " feat. ",
+    " ft. ",
+    " featuring ",
+    " & ",
+    " and ",
+    " x ",
+    " vs. ",
+    " with ",
+    ", ",
+];
+
+/// Splits an artist credit at its join phrases
+fn split_credit(credit: &str) -> Vec<String> {
+    let lowercase = credit.to_ascii_lowercase();
+    let mut names = Vec::new();
+    let mut start = 0;
+    while let Some((pos, phrase)) = JOIN_PHRASES
+        .iter()
+        .filter_map(|phrase| Some((lowercase[start..].find(phrase)? + start, phrase)))
+        .min()
+    {
+        names.push(credit[start..pos].trim().to_string());
+        start = pos + phrase.len();
+    }
+    names.push(credit[start..].trim().to_string());
+    names
+}
+
+/// Credits several artists the way MusicBrainz does, as in `A, B & C`
+fn join_credit(names: &[String]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => name.clone(),
+        [names @ .., last] => format!("{} & {}", names.join(", "), last),
+    }
+}
+
+/// Lines the artists up with `artist_mbids`, one name for each MBID. Picard's `ARTISTS` tag has
+/// them as they are, and the `ARTIST` credit with its join phrases is left alone. Without
+/// `ARTISTS`, the credit is split at its join phrases, as long as that gives a name for every MBID.
+fn credit_artists(metadata: &mut TrackMetadata) {
+    let info = &mut metadata.additional_info;
+    // Several `ARTIST` tags, rather than one credit
+    if metadata.artist_name.contains(';') {
+        let names = split_values(&metadata.artist_name);
+        metadata.artist_name = join_credit(&names);
+        if info.artist_names.is_empty() {
+            info.artist_names = names;
         }
-        std::fs::read_dir(cache_path)
-            .unwrap()
-            .try_for_each(|i| std::fs::remove_file(i?.path()))
-            .unwrap();
+    }
+    if !info.artist_names.is_empty() {
+        if metadata.artist_name.is_empty() {
+            metadata.artist_name = join_credit(&info.artist_names);
+        }
+        return;
+    }
+    if metadata.artist_name.is_empty() || info.artist_mbids.is_empty() {
+        return;
+    }
+    let names = match info.artist_mbids.len() {
+        1 => vec![metadata.artist_name.clone()],
+        _ => split_credit(&metadata.artist_name),
+    };
+    if names.len() == info.artist_mbids.len() {
+        info.artist_names = names;
     }
 }
 
-fn read_recording_id(filename: &str, data: &mut ListenbrainzData) -> Result<(), ()> {
-    let Ok(tag) = Tag::read_from_path(filename) else {
-        return Err(());
+/// Whether the current file has a real video track, as opposed to cover art or a still image, and
+/// whether it has an audio track at all
+fn inspect_tracks(mpv: &Mpv) -> (bool, bool) {
+    let Ok(tracks) = mpv.get_property::<libmpv::MpvNode>("track-list") else {
+        return (false, true);
     };
+    let (mut video, mut audio) = (false, false);
+    for track in tracks.to_array().into_iter().flatten() {
+        let Some(properties) = track.to_map() else {
+            continue;
+        };
+        let (mut kind, mut still) = (None, false);
+        for (key, value) in properties {
+            match key {
+                "type" => kind = value.to_str().map(str::to_string),
+                "albumart" | "image" => still |= value.to_bool().unwrap_or(false),
+                _ => {}
+            }
+        }
+        match kind.as_deref() {
+            Some("video") if !still => video = true,
+            Some("audio") => audio = true,
+            _ => {}
+        }
+    }
+    (video, audio)
+}
 
-    for f in tag.frames() {
-        if f.id() == "UFID" {
-            let Content::Unknown(ref u) = f.content() else {
-                continue;
-            };
+/// Start times of the chapters of the current file, if it has more than one and `policy` lets it
+/// be scrobbled chapter by chapter
+fn chapter_starts(mpv: &Mpv, policy: ChapterPolicy) -> Vec<f64> {
+    if policy == ChapterPolicy::Never {
+        return Vec::new();
+    }
+    let Ok(chapters) = mpv.get_property::<libmpv::MpvNode>("chapter-list") else {
+        return Vec::new();
+    };
+    let mut titled = true;
+    let starts: Vec<f64> = chapters
+        .to_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|chapter| {
+            let chapter: Vec<_> = chapter.to_map()?.collect();
+            titled &= chapter.iter().any(|(key, title)| {
+                *key == "title" && title.to_str().is_some_and(|t| !t.is_empty())
+            });
+            chapter
+                .iter()
+                .find(|(key, _)| *key == "time")
+                .and_then(|(_, time)| time.to_f64())
+        })
+        .collect();
+    if starts.len() > 1 && (titled || policy == ChapterPolicy::Always) {
+        starts
+    } else {
+        Vec::new()
+    }
+}
 
-            let Some(delimeter_pos) = memchr::memchr(0, &u.data) else {
-                continue;
-            };
+/// Templates for ICY titles of the form `artist<separator>title`
+fn icy_templates(separators: &[&str]) -> Vec<Template> {
+    separators
+        .iter()
+        .map(|separator| Template::new(&format!("%artist%{}%title%", separator)))
+        .collect()
+}
 
-            if &u.data[..delimeter_pos] != b"http://musicbrainz.org" {
-                continue;
+/// Splits the ICY title into artist and title, using the rules for the station before the general
+/// separators
+fn parse_icy_title(data: &mut ListenbrainzData) {
+    let metadata = &mut data.payload.track_metadata;
+    metadata.artist_name.clear();
+    metadata.track_name.clear();
+    metadata.release_name.clear();
+    metadata.additional_info = AdditionalInfo::default();
+
+    let station_templates = data
+        .icy_rules
+        .iter()
+        .filter(|(station, _)| data.station.contains(station.as_str()))
+        .map(|(_, template)| template);
+    for template in station_templates.chain(&data.icy_templates) {
+        let Some(captures) = template.captures(&data.icy_title) else {
+            continue;
+        };
+        for (field, value) in captures {
+            match field {
+                "artist" => metadata.artist_name = value.to_string(),
+                "title" => metadata.track_name = value.to_string(),
+                "album" => metadata.release_name = value.to_string(),
+                _ => {}
+            }
+        }
+        return;
+    }
+}
+
+/// Fills in the artist, title and album the tags left out, from the title of the playlist entry,
+/// the path templates and the file name, in that order
+fn infer_metadata(data: &mut ListenbrainzData, path: &str, playlist_title: Option<&str>) {
+    let metadata = &mut data.payload.track_metadata;
+    let path = Path::new(path).with_extension("");
+    let components: Vec<&str> = path.iter().filter_map(|c| c.to_str()).collect();
+
+    let mut inputs: Vec<(&Template, String)> = Vec::new();
+    if let Some(title) = playlist_title {
+        inputs.extend(data.icy_templates.iter().map(|t| (t, title.to_string())));
+    }
+    for (depth, template) in &data.path_templates {
+        if let Some(start) = components.len().checked_sub(*depth) {
+            inputs.push((template, components[start..].join("/")));
+        }
+    }
+    if let Some(file_stem) = components.last() {
+        inputs.extend(
+            data.icy_templates
+                .iter()
+                .map(|t| (t, file_stem.to_string())),
+        );
+    }
+
+    // Later templates can still fill in what earlier ones left out, like the album from the path
+    // after the artist and title from the playlist
+    for (template, input) in &inputs {
+        if !metadata.artist_name.is_empty()
+            && !metadata.track_name.is_empty()
+            && !metadata.release_name.is_empty()
+        {
+            break;
+        }
+        let Some(captures) = template.captures(input) else {
+            continue;
+        };
+        for (field, value) in captures {
+            let tag = match field {
+                "artist" => &mut metadata.artist_name,
+                "title" => &mut metadata.track_name,
+                "album" => &mut metadata.release_name,
+                _ => continue,
+            };
+            if tag.is_empty() {
+                *tag = value.to_string();
+                metadata.additional_info.metadata_inferred = true;
             }
+        }
+    }
+}
 
-            data.payload.track_metadata.additional_info.recording_mbid =
-                if let Ok(s) = std::str::from_utf8(&u.data[delimeter_pos + 1..]) {
-                    s.to_string()
-                } else {
-                    continue;
-                };
+/// Takes a tag into `metadata`, by the field its key is an alias of
+fn apply_tag(metadata: &mut TrackMetadata, field: Field, value: &str) {
+    let info = &mut metadata.additional_info;
+    match field {
+        Field::Title => metadata.track_name = value.to_string(),
+        Field::Artist => metadata.artist_name = value.to_string(),
+        Field::Artists => info.artist_names = split_values(value),
+        Field::Album => metadata.release_name = value.to_string(),
+        Field::AlbumArtist => info.release_artist_name = value.to_string(),
+        Field::AlbumArtists => info.release_artist_names = split_values(value),
+        Field::Genre => info.tags = split_values(value),
+        // Numbers may come with the total, as in `3/12`
+        Field::TrackNumber => {
+            info.tracknumber = value.split('/').next().unwrap().trim().to_string()
+        }
+        Field::DiscNumber => info.discnumber = value.split('/').next().unwrap().trim().to_string(),
+        Field::Isrc => info.isrc = value.to_string(),
+        Field::RecordingMbid => info.recording_mbid = value.to_string(),
+        Field::ReleaseMbid => info.release_mbid = value.to_string(),
+        Field::ArtistMbids => {
+            #[cfg(debug_assertions)]
+            dbg!(value);
 
-            return Ok(());
+            info.artist_mbids = split_mbids(value);
         }
+        Field::ReleaseGroupMbid => info.release_group_mbid = value.to_string(),
+        Field::TrackMbid => info.track_mbid = value.to_string(),
+        Field::WorkMbids => info.work_mbids = split_mbids(value),
     }
-
-    Err(())
 }
 
-macro_rules! scrobble_duration {
-    ($duration:expr,$speed:expr) => {
-        if $duration <= 40.0 {
-            $duration - 1.0
-        } else {
-            f64::min(240.0, $duration / 2.0)
-        } / $speed
-    };
-}
+/// Seconds from where playback started within which a seek counts as going back to the start
+const REPLAY_WINDOW: f64 = 2.0;
 
 #[no_mangle]
 pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
     let mut mpv = ManuallyDrop::new(Mpv::new_with_context(ctx).unwrap());
+    mpv.event_context()
+        .observe_property("core-idle", libmpv::Format::Flag, 0)
+        .unwrap();
     mpv.event_context()
         .observe_property("pause", libmpv::Format::Flag, 0)
         .unwrap();
     mpv.event_context()
         .observe_property("speed", libmpv::Format::Double, 0)
         .unwrap();
+    mpv.event_context()
+        .observe_property("metadata", libmpv::Format::Node, 0)
+        .unwrap();
+    mpv.event_context()
+        .observe_property("chapter", libmpv::Format::Int64, 0)
+        .unwrap();
+    mpv.event_context()
+        .observe_property("aid", libmpv::Format::String, 0)
+        .unwrap();
+    mpv.event_context()
+        .observe_property("ab-loop-a", libmpv::Format::String, 0)
+        .unwrap();
+    mpv.event_context()
+        .observe_property("ab-loop-b", libmpv::Format::String, 0)
+        .unwrap();
     let mut event_loop = EventLoop::<ListenbrainzData>::try_new().unwrap();
     let handle = event_loop.handle();
-    let timer = Timer::from_duration(Duration::from_secs(31_536_000));
-    let mut timer = handle
-        .insert_source(timer, |_event, _metadata, _data| {
-            panic!(
-                "Something has gone horibbly wrong, somehow, mpv has been loading for an entire \
-                 year!"
-            );
-        })
-        .unwrap();
     let (tx, rx): (Sender<()>, Channel<()>) = calloop::channel::channel();
     mpv.event_context_mut()
         .set_wakeup_callback(move || tx.send(()).unwrap());
@@ -259,26 +1423,230 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
         _metadata: &mut (),
         data: &mut ListenbrainzData,
     ) -> calloop::timer::TimeoutAction {
-        if data.scrobble {
-            data.payload.listened_at = NonZeroU64::new(
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            );
-            scrobble(
-                "single",
-                &data.payload,
-                data.online,
-                &data.token,
-                &data.cache_path,
+        if data.scrobble && !scrobble_if_played(data) {
+            if let Some(deadline) = data.play_time.reaches(data.threshold) {
+                return calloop::timer::TimeoutAction::ToInstant(deadline);
+            }
+        }
+        data.timer = None;
+        calloop::timer::TimeoutAction::Drop
+    }
+
+    /// Submits the listen once enough of the track has been played
+    fn scrobble_if_played(data: &mut ListenbrainzData) -> bool {
+        // Timers may fire a hair early
+        if data.play_time.played() < data.threshold - 0.01 {
+            return false;
+        }
+        data.payload.listened_at = NonZeroU64::new(
+            data.play_time
+                .started
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        );
+        scrobble("single", data);
+        data.scrobble = false;
+        true
+    }
+
+    /// Points the scrobble timer at the moment the threshold will be reached
+    fn reschedule(data: &mut ListenbrainzData, handle: &LoopHandle<'static, ListenbrainzData>) {
+        if let Some(timer) = data.timer.take() {
+            handle.remove(timer);
+        }
+        if !data.scrobble {
+            return;
+        }
+        if let Some(deadline) = data.play_time.reaches(data.threshold) {
+            data.timer = Some(
+                handle
+                    .insert_source(Timer::from_deadline(deadline), timer_event)
+                    .unwrap(),
             );
         }
+    }
+
+    /// Starts a new pass through the current track, which is scrobbled on its own
+    fn replay(data: &mut ListenbrainzData) {
+        data.play_time.restart();
+        data.scrobble = data.scrobblable;
+        playing_now(data);
+    }
+
+    /// Whether an A-B loop is repeating a section of the track
+    fn ab_loop_active(mpv: &Mpv) -> bool {
+        ["ab-loop-a", "ab-loop-b"]
+            .iter()
+            .all(|point| mpv.get_property::<String>(point).is_ok_and(|p| p != "no"))
+    }
+
+    /// Treats a new ICY title as the start of a new track
+    fn stream_title_changed(data: &mut ListenbrainzData, icy_title: String) {
+        if data.scrobble {
+            scrobble_if_played(data);
+        }
+        data.icy_title = icy_title;
+        parse_icy_title(data);
+        data.threshold = data.stream_threshold;
+        // Stations don't announce albums or MBIDs
+        data.scrobblable = !data.excluded
+            && !data.skipped()
+            && data
+                .requirement
+                .min(Requirement::ArtistTitle)
+                .satisfied_by(&data.payload.track_metadata);
+        replay(data);
+    }
+
+    /// Treats the current chapter as a track of `data.album`, with the chapter's own metadata and
+    /// length
+    fn chapter_changed(mpv: &Mpv, data: &mut ListenbrainzData) {
+        if data.scrobble {
+            scrobble_if_played(data);
+        }
+        let metadata = &mut data.payload.track_metadata;
+        *metadata = data.album.clone();
+        // Identifiers of the file's own recording don't belong to its chapters
+        let info = &mut metadata.additional_info;
+        info.recording_mbid.clear();
+        info.track_mbid.clear();
+        info.work_mbids.clear();
+        info.isrc.clear();
+        info.tracknumber = usize::try_from(data.chapter)
+            .map(|chapter| (chapter + 1).to_string())
+            .unwrap_or_default();
+        // The title of the whole file is the album's, if it isn't tagged otherwise
+        let title = std::mem::take(&mut metadata.track_name);
+        if metadata.release_name.is_empty() {
+            metadata.release_name = title;
+        }
+
+        let chapter = usize::try_from(data.chapter)
+            .ok()
+            .filter(|&chapter| chapter < data.chapters.len());
+        let mut threshold = None;
+        if let Some(chapter) = chapter {
+            // The album's artists don't belong to a guest artist on a compilation
+            let info = &mut metadata.additional_info;
+            let album_mbids = std::mem::take(&mut info.artist_mbids);
+            let album_names = std::mem::take(&mut info.artist_names);
+            if let Some(tags) = mpv
+                .get_property::<libmpv::MpvNode>("chapter-metadata")
+                .ok()
+                .as_ref()
+                .and_then(|tags| tags.to_map())
+            {
+                for (key, value) in tags {
+                    let Some(value) = value.to_str() else {
+                        continue;
+                    };
+                    // CUE sheets credit a track's artist as its performer
+                    let field = if key.eq_ignore_ascii_case("performer") {
+                        Some(Field::Artist)
+                    } else {
+                        data.aliases.field(key)
+                    };
+                    if let Some(field) = field {
+                        apply_tag(metadata, field, value);
+                    }
+                }
+            }
+            let info = &mut metadata.additional_info;
+            if metadata.artist_name == data.album.artist_name {
+                if info.artist_mbids.is_empty() {
+                    info.artist_mbids = album_mbids;
+                }
+                if info.artist_names.is_empty() {
+                    info.artist_names = album_names;
+                }
+            }
+            credit_artists(metadata);
+
+            let start = data.chapters[chapter];
+            let length = data
+                .chapters
+                .get(chapter + 1)
+                .copied()
+                .unwrap_or(data.duration)
+                - start;
+            threshold = data.thresholds.threshold(length);
+            metadata.additional_info.duration_ms = Some((length * 1000.0) as u64);
+        }
+
+        data.threshold = threshold.unwrap_or_default();
+        data.lookup_pending = None;
+        let allowed = !data.excluded && threshold.is_some() && !data.skipped();
+        if allowed {
+            look_up(data);
+        }
+        // Chapters can't be tagged with their own Recording MBID, unless a lookup found it, so
+        // the album's Release MBID stands in for it
+        let metadata = &data.payload.track_metadata;
+        data.scrobblable = allowed
+            && (data.requirement.satisfied_by(metadata)
+                || data
+                    .requirement
+                    .min(Requirement::ReleaseMbid)
+                    .satisfied_by(metadata));
+        // Seeking back to the start of the chapter replays it
+        data.start_pos = chapter.map(|chapter| data.chapters[chapter]);
+        data.seek_from = None;
+        replay(data);
+    }
+
+    /// Forgets everything about the file that was playing, so that nothing lingers into the next
+    fn reset_file(data: &mut ListenbrainzData, handle: &LoopHandle<'static, ListenbrainzData>) {
+        data.payload.track_metadata = TrackMetadata::default();
+        data.tags.clear();
+        data.path.clear();
+        data.loaded = false;
+        data.resume = None;
+        data.playing_now_at = None;
+        data.scrobblable = false;
         data.scrobble = false;
-        calloop::timer::TimeoutAction::Drop
+        data.excluded = false;
+        data.play_time = PlayTime::default();
+        data.threshold = 0.0;
+        data.start_pos = None;
+        data.seek_from = None;
+        data.stream = false;
+        data.chapters.clear();
+        data.chapter = -1;
+        data.lookup_pending = None;
+        reschedule(data, handle);
+    }
+
+    fn update_play_time(mpv: &Mpv, data: &mut ListenbrainzData, seeked: bool) {
+        // Repetitions of an A-B loop aren't listens of the track, and neither is playback with the
+        // audio turned off
+        let playing = !mpv.get_property::<bool>("core-idle").unwrap_or(true)
+            && !ab_loop_active(mpv)
+            && mpv.get_property::<i64>("current-tracks/audio/id").is_ok();
+        let pos = mpv.get_property::<f64>("time-pos").unwrap_or(0.0);
+        data.play_time.update(playing, pos, seeked);
     }
 
-    let mut data = ListenbrainzData::default();
+    let (ping, ping_source) = calloop::ping::make_ping().unwrap();
+    let ping_handle = event_loop.handle();
+    handle
+        .insert_source(ping_source, move |_event, _metadata, data| {
+            dispatch(data, &ping_handle)
+        })
+        .unwrap();
+
+    let (reschedule_ping, reschedule_source) = calloop::ping::make_ping().unwrap();
+    let reschedule_handle = event_loop.handle();
+    handle
+        .insert_source(reschedule_source, move |_event, _metadata, data| {
+            reschedule(data, &reschedule_handle)
+        })
+        .unwrap();
+
+    let mut data = ListenbrainzData::new(ping, reschedule_ping);
+    let mut http_config = http::Config::default();
+    let mut remember_private = false;
 
     for i in mpv
         .get_property::<libmpv::MpvNode>("script-opts")
@@ -301,13 +1669,183 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
                     data.cache_path = Path::new(i.1.to_str().unwrap()).join("listenbrainz");
                 }
             }
+            "listenbrainz-api-url" => {
+                http_config.api_url = i.1.to_str().unwrap().trim_end_matches('/').to_string()
+            }
+            "listenbrainz-connect-timeout" => {
+                if let Some(timeout) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    http_config.connect_timeout = timeout;
+                }
+            }
+            "listenbrainz-timeout" => {
+                if let Some(timeout) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    http_config.timeout = timeout;
+                }
+            }
+            "listenbrainz-keep-alive" => http_config.keep_alive = i.1.to_str().unwrap() != "no",
+            "listenbrainz-proxy" => match http::Proxy::parse(i.1.to_str().unwrap()) {
+                Ok(proxy) => http_config.proxy = Some(proxy),
+                Err(e) => eprintln!("Invalid proxy {:?}: {}", i.1.to_str().unwrap(), e),
+            },
+            "listenbrainz-ca-bundle" => {
+                http_config.ca_bundle = Some(PathBuf::from(i.1.to_str().unwrap()))
+            }
+            "listenbrainz-batch" => data.batch = i.1.to_str().unwrap() == "yes",
+            "listenbrainz-batch-interval" => {
+                if let Some(interval) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    data.batch_interval = interval;
+                }
+            }
+            "listenbrainz-playing-now-interval" => {
+                if let Some(interval) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    data.playing_now_interval = interval;
+                }
+            }
+            "listenbrainz-batch-size" => match i.1.to_str().unwrap().parse() {
+                Ok(size) if size > 0 => data.batch_size = size,
+                _ => eprintln!("Invalid batch size {:?}", i.1.to_str().unwrap()),
+            },
+            "listenbrainz-icy-separators" => {
+                data.icy_templates = icy_templates(
+                    &i.1.to_str()
+                        .unwrap()
+                        .split(';')
+                        .filter(|separator| !separator.is_empty())
+                        .collect::<Vec<_>>(),
+                )
+            }
+            "listenbrainz-icy-rules" => {
+                data.icy_rules =
+                    i.1.to_str()
+                        .unwrap()
+                        .split(';')
+                        .filter_map(|rule| {
+                            let (station, template) = rule.split_once('|')?;
+                            Some((station.trim().to_lowercase(), Template::new(template)))
+                        })
+                        .collect()
+            }
+            "listenbrainz-include" => {
+                for pattern in i.1.to_str().unwrap().split(';') {
+                    data.path_rules.include(pattern);
+                }
+            }
+            "listenbrainz-exclude" => {
+                for pattern in i.1.to_str().unwrap().split(';') {
+                    data.path_rules.exclude(pattern);
+                }
+            }
+            "listenbrainz-skip" => add_skip_filter(&mut data, i.1.to_str().unwrap()),
+            "listenbrainz-tag-aliases" => {
+                for alias in i.1.to_str().unwrap().split(';') {
+                    if alias.trim().is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = data.aliases.add(alias) {
+                        eprintln!("Invalid tag alias {:?}: {}", alias, e);
+                    }
+                }
+            }
+            "listenbrainz-lookup" => data.lookup = i.1.to_str().unwrap() == "yes",
+            "listenbrainz-lookup-confidence" => match i.1.to_str().unwrap().parse() {
+                Ok(confidence) if (0.0..=1.0).contains(&confidence) => {
+                    data.lookup_confidence = confidence
+                }
+                _ => eprintln!("Invalid lookup confidence {:?}", i.1.to_str().unwrap()),
+            },
+            "listenbrainz-remember-private" => remember_private = i.1.to_str().unwrap() == "yes",
+            "listenbrainz-video" => {
+                data.video_policy = match i.1.to_str().unwrap() {
+                    "yes" => VideoPolicy::Always,
+                    "mbid" => VideoPolicy::Mbid,
+                    "no" => VideoPolicy::Never,
+                    policy => {
+                        eprintln!("Unknown video policy {:?}, using \"mbid\"", policy);
+                        VideoPolicy::Mbid
+                    }
+                }
+            }
+            "listenbrainz-chapters" => {
+                data.chapter_policy = match i.1.to_str().unwrap() {
+                    "yes" => ChapterPolicy::Always,
+                    "titled" => ChapterPolicy::Titled,
+                    "no" => ChapterPolicy::Never,
+                    policy => {
+                        eprintln!("Unknown chapter policy {:?}, using \"titled\"", policy);
+                        ChapterPolicy::Titled
+                    }
+                }
+            }
+            "listenbrainz-infer" => data.infer = i.1.to_str().unwrap() == "yes",
+            "listenbrainz-path-templates" => {
+                data.path_templates =
+                    i.1.to_str()
+                        .unwrap()
+                        .split(';')
+                        .filter(|template| !template.is_empty())
+                        .map(|template| (template.split('/').count(), Template::new(template)))
+                        .collect()
+            }
+            "listenbrainz-stream-threshold" => {
+                if let Some(threshold) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    data.stream_threshold = threshold.as_secs_f64();
+                }
+            }
+            "listenbrainz-threshold-percent" => match i.1.to_str().unwrap().parse() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => {
+                    data.thresholds.percent = percent
+                }
+                _ => eprintln!("Invalid threshold percentage {:?}", i.1.to_str().unwrap()),
+            },
+            "listenbrainz-threshold-max" => {
+                if let Some(max) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    data.thresholds.max = max.as_secs_f64();
+                }
+            }
+            "listenbrainz-min-length" => match i.1.to_str().unwrap() {
+                "0" => data.thresholds.min_length = 0.0,
+                min_length => {
+                    if let Some(min_length) = parse_seconds(i.0, min_length) {
+                        data.thresholds.min_length = min_length.as_secs_f64();
+                    }
+                }
+            },
+            "listenbrainz-max-length" => {
+                if let Some(max_length) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    data.thresholds.max_length = Some(max_length.as_secs_f64());
+                }
+            }
+            "listenbrainz-long-threshold" => {
+                if let Some(long) = parse_seconds(i.0, i.1.to_str().unwrap()) {
+                    data.thresholds.long = Some(long.as_secs_f64());
+                }
+            }
+            "listenbrainz-metered" => {
+                data.metered_policy = match i.1.to_str().unwrap() {
+                    "cache" => MeteredPolicy::Cache,
+                    "single" => MeteredPolicy::Single,
+                    "ignore" => MeteredPolicy::Ignore,
+                    policy => {
+                        eprintln!("Unknown metered policy {:?}, using \"single\"", policy);
+                        MeteredPolicy::Single
+                    }
+                }
+            }
             _ => {}
         }
     }
 
+    data.http.configure(http_config, data.token.clone());
+
     if let Ok(config_dir) = mpv.get_property::<MpvStr>("config-dir") {
         if !config_dir.is_empty() {
             data.cache_path = Path::new(&*config_dir).join("listenbrainz");
+            read_config_file(
+                &Path::new(&*config_dir)
+                    .join("script-opts")
+                    .join("listenbrainz.conf"),
+                &mut data,
+            );
         }
     }
 
@@ -315,12 +1853,57 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
         std::fs::create_dir(&data.cache_path).unwrap();
     }
 
+    if remember_private {
+        let private_path = beside_cache(&data.cache_path, "private");
+        data.private = private_path.exists();
+        data.private_path = Some(private_path);
+    }
+    data.lookups = lookup::Cache::load(beside_cache(&data.cache_path, "lookups"));
+
     handle
         .insert_source(rx, move |_event, _metadata, data| loop {
             match mpv.event_context_mut().wait_event(0.0) {
                 Some(Ok(Event::Shutdown)) => signal.stop(),
                 Some(Ok(Event::ClientMessage(m))) => {
-                    if m[0] == "key-binding" {
+                    if m[0] == "listenbrainz-offline" {
+                        let offline = match m.get(1).copied() {
+                            Some("yes") => true,
+                            Some("no") => false,
+                            _ => !data.offline,
+                        };
+                        set_offline(&mpv, data, offline);
+                    } else if m[0] == "listenbrainz-private" {
+                        let private = match m.get(1).copied() {
+                            Some("yes") => true,
+                            Some("no") => false,
+                            _ => !data.private,
+                        };
+                        set_private(&mpv, data, private);
+                    } else if m[0] == "listenbrainz-test-filter" {
+                        let expression = m.get(1).copied().unwrap_or_default();
+                        let text = match filter::Filter::parse(expression) {
+                            Ok(filter) if filter.matches(&data.filter_fields()) => {
+                                format!("{} matches the current track", expression)
+                            }
+                            Ok(_) => format!("{} doesn't match the current track", expression),
+                            Err(e) => format!("Invalid filter {}: {}", expression, e),
+                        };
+                        eprintln!("{}", text);
+                        show_text(&mpv, &text);
+                    } else if m[0] == "key-binding" {
+                        if m[1] == "listenbrainz-offline" {
+                            if !m.get(2).is_some_and(|state| state.starts_with('u')) {
+                                set_offline(&mpv, data, !data.offline);
+                            }
+                            continue;
+                        }
+                        if m[1] == "listenbrainz-private" {
+                            if !m.get(2).is_some_and(|state| state.starts_with('u')) {
+                                set_private(&mpv, data, !data.private);
+                            }
+                            continue;
+                        }
+
                         let score = match m[1] {
                             "listenbrainz-love" => 1,
                             "listenbrainz-hate" => -1,
@@ -350,219 +1933,297 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
                             score,
                         };
 
-                        if !data.online {
+                        if !data.connected() {
                             eprintln!("You must be online to submit feedback");
                             continue;
                         }
 
-                        let status = ureq::post(
-                            "https://api.listenbrainz.org/1/feedback/recording-feedback",
-                        )
-                        .set("Authorization", &data.token)
-                        .send_json(feedback);
-
-                        if status.is_err() {
-                            eprintln!("Error submitting feedback: {:?}", status);
-                        } else {
-                            eprintln!("Feedback submitted successfully");
-                        }
+                        let body = serde_json::to_vec(&feedback).unwrap();
+                        data.http.enqueue(
+                            http::Request::post("/1/feedback/recording-feedback", body),
+                            Completion::Feedback,
+                        );
                     }
                 }
                 Some(Ok(Event::PropertyChange { name, change, .. })) => {
-                    if name == "pause" && data.scrobble {
+                    if name == "core-idle"
+                        || name == "ab-loop-a"
+                        || name == "ab-loop-b"
+                        || name == "aid"
+                    {
+                        update_play_time(&mpv, data, false);
+                        reschedule(data, &rx_handle);
+                    } else if name == "pause" {
                         let PropertyData::Flag(paused) = change else {
                             unreachable!();
                         };
 
-                        if paused {
-                            data.pause_instant = Instant::now();
-                            rx_handle.remove(timer);
-                        } else {
-                            data.scrobble_deadline =
-                                data.scrobble_deadline + data.pause_instant.elapsed();
-                            timer = rx_handle
-                                .insert_source(
-                                    Timer::from_deadline(data.scrobble_deadline),
-                                    timer_event,
-                                )
-                                .unwrap();
+                        data.paused = paused;
+                        if !paused {
+                            playing_now(data);
                         }
-                    } else if name == "speed" && data.scrobble {
+                    } else if name == "speed" {
                         let PropertyData::Double(speed) = change else {
                             unreachable!();
                         };
 
-                        let duration = mpv.get_property::<f64>("duration").unwrap();
-                        let pos = mpv.get_property::<f64>("time-pos").unwrap();
-                        data.scrobble_deadline = Instant::now()
-                            + Duration::from_secs_f64(scrobble_duration!(duration, speed) - pos);
-                        data.payload.track_metadata.additional_info.duration_ms =
-                            (duration * 1000.0) as u64;
-                        timer = rx_handle
-                            .insert_source(
-                                Timer::from_deadline(data.scrobble_deadline),
-                                timer_event,
-                            )
-                            .unwrap();
+                        update_play_time(&mpv, data, false);
+                        data.play_time.speed = speed;
+                        reschedule(data, &rx_handle);
+                    } else if name == "chapter" && !data.chapters.is_empty() {
+                        let PropertyData::Int64(chapter) = change else {
+                            continue;
+                        };
+
+                        if chapter != data.chapter {
+                            update_play_time(&mpv, data, false);
+                            data.chapter = chapter;
+                            chapter_changed(&mpv, data);
+                            reschedule(data, &rx_handle);
+                        }
+                    } else if name == "metadata" && data.stream {
+                        let icy_title = mpv
+                            .get_property::<String>("metadata/by-key/icy-title")
+                            .unwrap_or_default();
+                        if icy_title != data.icy_title {
+                            update_play_time(&mpv, data, false);
+                            stream_title_changed(data, icy_title);
+                            reschedule(data, &rx_handle);
+                        }
                     }
                 }
                 Some(Ok(Event::Seek)) => {
-                    if mpv.get_property::<i64>("time-pos").unwrap() == 0 {
-                        rx_handle.remove(timer);
-                        let duration = mpv.get_property::<f64>("duration").unwrap();
-                        let speed = mpv.get_property::<f64>("speed").unwrap();
-
-                        data.scrobble_deadline = Instant::now()
-                            + Duration::from_secs_f64(scrobble_duration!(duration, speed));
-                        data.payload.track_metadata.additional_info.duration_ms =
-                            (duration * 1000.0) as u64;
-                        timer = rx_handle
-                            .insert_source(
-                                Timer::from_deadline(data.scrobble_deadline),
-                                timer_event,
-                            )
-                            .unwrap();
+                    if data.seek_from.is_none() {
+                        data.seek_from = Some(data.play_time.position());
                     }
+                    update_play_time(&mpv, data, true);
+                    reschedule(data, &rx_handle);
                 }
-                Some(Ok(Event::FileLoaded)) => {
-                    let audio_pts: Result<i64, libmpv::Error> = mpv.get_property("audio-pts");
-                    if audio_pts.is_err() || audio_pts.unwrap() < 1 {
-                        rx_handle.remove(timer);
-
-                        data.payload.track_metadata.additional_info.release_mbid = String::new();
-                        data.payload.track_metadata.additional_info.artist_mbids = Vec::new();
-                        data.payload.track_metadata.additional_info.recording_mbid = String::new();
-                        data.payload.track_metadata.artist_name = String::new();
-                        data.payload.track_metadata.track_name = String::new();
-                        data.payload.track_metadata.release_name = String::new();
-                        for i in mpv
-                            .get_property::<libmpv::MpvNode>("metadata")
-                            .unwrap()
-                            .to_map()
-                            .unwrap()
+                Some(Ok(Event::PlaybackRestart)) => {
+                    // The position mpv settled on after a seek
+                    update_play_time(&mpv, data, true);
+                    let pos = data.play_time.pos;
+                    if let Some(session) = data.resume.take() {
+                        let metadata = &data.payload.track_metadata;
+                        if session.chapter == data.chapter
+                            && session.artist_name == metadata.artist_name
+                            && session.track_name == metadata.track_name
+                            && (pos - session.position).abs() < RESUME_WINDOW
+                            && data.scrobble
                         {
-                            #[cfg(debug_assertions)]
-                            dbg!(i.0);
-                            match i.0 {
-                                "MUSICBRAINZ_ALBUMID" | "MusicBrainz Album Id" => {
-                                    data.payload.track_metadata.additional_info.release_mbid =
-                                        i.1.to_str().unwrap().to_string()
-                                }
-                                "MUSICBRAINZ_ARTISTID" | "MusicBrainz Artist Id" => {
-                                    let artists = i.1.to_str().unwrap();
-
-                                    #[cfg(debug_assertions)]
-                                    dbg!(artists);
-
-                                    data.payload.track_metadata.additional_info.artist_mbids =
-                                        if memchr::memchr(b';', artists.as_bytes()).is_some() {
-                                            i.1.to_str()
-                                                .unwrap()
-                                                .split(";")
-                                                .map(|f| f.trim().to_string())
-                                                .collect()
-                                        } else {
-                                            i.1.to_str()
-                                                .unwrap()
-                                                .split("/")
-                                                .map(|f| f.trim().to_string())
-                                                .collect()
-                                        };
-                                }
-                                "MUSICBRAINZ_TRACKID" | "http://musicbrainz.org" => {
-                                    data.payload.track_metadata.additional_info.recording_mbid =
-                                        i.1.to_str().unwrap().to_string();
-                                }
-                                "ARTIST" | "artist" => {
-                                    data.payload.track_metadata.artist_name =
-                                        i.1.to_str().unwrap().to_string();
-                                }
-                                "TITLE" | "title" => {
-                                    data.payload.track_metadata.track_name =
-                                        i.1.to_str().unwrap().to_string();
-                                }
-                                "ALBUM" | "album" => {
-                                    data.payload.track_metadata.release_name =
-                                        i.1.to_str().unwrap().to_string();
-                                }
-                                _ => {}
-                            }
+                            data.play_time.played = session.played;
+                            data.play_time.started =
+                                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(session.started));
                         }
-
-                        #[cfg(debug_assertions)]
+                    }
+                    let start_pos = *data.start_pos.get_or_insert(pos);
+                    // `loop-file` and seeking back to the start both land near where playback
+                    // started, which is a replay if the track had got anywhere by then
+                    if let Some(from) = data.seek_from.take() {
+                        if (pos - start_pos).abs() < REPLAY_WINDOW
+                            && from - pos > REPLAY_WINDOW
+                            && !ab_loop_active(&mpv)
                         {
-                            dbg!(
-                                *mpv.get_property::<MpvStr>("filename").unwrap()
-                                    != data.payload.track_metadata.track_name
-                            );
-                            dbg!(!data.payload.track_metadata.artist_name.is_empty());
-                            dbg!(!data.payload.track_metadata.track_name.is_empty());
-                            dbg!(!data.payload.track_metadata.release_name.is_empty());
-                            #[cfg(feature = "only-scrobble-if-mbid")]
-                            dbg!(!data
-                                .payload
-                                .track_metadata
-                                .additional_info
-                                .release_mbid
-                                .is_empty());
+                            replay(data);
+                        }
+                    }
+                    reschedule(data, &rx_handle);
+                }
+                Some(Ok(Event::StartFile)) => reset_file(data, &rx_handle),
+                Some(Ok(Event::EndFile(reason))) => {
+                    let position = data.play_time.position();
+                    data.play_time.update(false, 0.0, true);
+                    match reason {
+                        // Nothing was heard of a file that failed to load or turned out to be a
+                        // playlist
+                        libmpv::mpv_end_file_reason::Error
+                        | libmpv::mpv_end_file_reason::Redirect => {}
+                        _ if !data.scrobble || scrobble_if_played(data) => {}
+                        // Quitting in the middle of a track may be picked up again on resume
+                        libmpv::mpv_end_file_reason::Quit if !data.stream => {
+                            let path = std::mem::take(&mut data.path);
+                            save_session(data, path, position);
+                        }
+                        _ => {}
+                    }
+                    reset_file(data, &rx_handle);
+                }
+                Some(Err(e)) if ends_file(&e) => {
+                    eprintln!("Playback ended with an error: {:?}", e);
+                    reset_file(data, &rx_handle);
+                }
+                Some(Err(e)) => eprintln!("Error in an mpv event: {:?}", e),
+                Some(Ok(Event::FileLoaded)) => {
+                    // Only the first load after `StartFile` is a new track
+                    if data.loaded {
+                        continue;
+                    }
+                    data.loaded = true;
+                    for i in mpv
+                        .get_property::<libmpv::MpvNode>("metadata")
+                        .unwrap()
+                        .to_map()
+                        .unwrap()
+                    {
+                        #[cfg(debug_assertions)]
+                        dbg!(i.0);
+                        let Some(value) = i.1.to_str() else {
+                            continue;
+                        };
+                        if let Some(field) = data.aliases.field(i.0) {
+                            apply_tag(&mut data.payload.track_metadata, field, value);
                         }
+                        data.tags.push((filter::field_name(i.0), value.to_string()));
+                    }
 
-                        data.scrobble = (*mpv.get_property::<MpvStr>("filename").unwrap()
-                            != data.payload.track_metadata.track_name)
-                            && !data.payload.track_metadata.artist_name.is_empty()
-                            && !data.payload.track_metadata.track_name.is_empty()
-                            && !data.payload.track_metadata.release_name.is_empty();
+                    // Streams have no duration, and a new track with every ICY title
+                    let duration = mpv.get_property::<f64>("duration").ok();
+                    let icy_title = mpv.get_property::<String>("metadata/by-key/icy-title").ok();
+                    data.stream = duration.is_none() || icy_title.is_some();
+                    if data.stream {
+                        data.station = format!(
+                            "{}\n{}",
+                            mpv.get_property::<String>("metadata/by-key/icy-name")
+                                .unwrap_or_default(),
+                            &*mpv.get_property::<MpvStr>("path").unwrap()
+                        )
+                        .to_lowercase();
+                        data.icy_title = icy_title.unwrap_or_default();
+                        parse_icy_title(data);
+                    }
 
-                        #[cfg(feature = "only-scrobble-if-mbid")]
-                        {
-                            data.scrobble = data.scrobble
-                                && !data
-                                    .payload
-                                    .track_metadata
-                                    .additional_info
-                                    .release_mbid
-                                    .is_empty();
+                    let path: MpvStr = mpv.get_property("path").unwrap();
+                    data.path = path.to_string();
+                    data.resume = load_session(&data.cache_path, &path);
+                    let (video, audio) = inspect_tracks(&mpv);
+                    // mpv leaves out some tags, like MP4's freeform ones, which only matter when
+                    // MBIDs are missing. The file's own tags go first, so mpv's take precedence.
+                    let additional_info = &data.payload.track_metadata.additional_info;
+                    if !data.stream
+                        && audio
+                        && !video
+                        && (additional_info.recording_mbid.is_empty()
+                            || additional_info.release_mbid.is_empty())
+                    {
+                        if let Ok(native) = tags::read(Path::new(&*path)) {
+                            let native: Vec<_> = native
+                                .into_iter()
+                                .map(|(key, value)| (filter::field_name(&key), value))
+                                .collect();
+                            let mut metadata = TrackMetadata::default();
+                            for (key, value) in native.iter().chain(&data.tags) {
+                                if let Some(field) = data.aliases.field(key) {
+                                    apply_tag(&mut metadata, field, value);
+                                }
+                            }
+                            data.payload.track_metadata = metadata;
+                            data.tags.splice(0..0, native);
                         }
+                    }
+                    credit_artists(&mut data.payload.track_metadata);
+                    if data.infer && !data.stream {
+                        let playlist_title =
+                            mpv.get_property::<i64>("playlist-pos")
+                                .ok()
+                                .and_then(|pos| {
+                                    mpv.get_property::<String>(&format!("playlist/{}/title", pos))
+                                        .ok()
+                                });
+                        infer_metadata(data, &path, playlist_title.as_deref());
+                    }
+                    // Profiles may have changed the requirements for this file
+                    data.requirement = requirement(&mpv, &path);
+                    if video && data.video_policy == VideoPolicy::Mbid {
+                        data.requirement = data.requirement.max(Requirement::RecordingMbid);
+                    }
 
-                        if data
-                            .payload
-                            .track_metadata
-                            .additional_info
-                            .recording_mbid
-                            .is_empty()
-                        {
-                            let filename: MpvStr = mpv.get_property("path").unwrap();
+                    #[cfg(debug_assertions)]
+                    {
+                        dbg!(
+                            *mpv.get_property::<MpvStr>("filename").unwrap()
+                                != data.payload.track_metadata.track_name
+                        );
+                        dbg!(!data.payload.track_metadata.artist_name.is_empty());
+                        dbg!(!data.payload.track_metadata.track_name.is_empty());
+                        dbg!(!data.payload.track_metadata.release_name.is_empty());
+                        dbg!(data.requirement);
+                    }
 
-                            let _ = read_recording_id(&filename, data);
-                        }
+                    // Streams never carry MBIDs
+                    data.excluded = !data
+                        .path_rules
+                        .allows(&absolute_path(&mpv, &path).to_string_lossy())
+                        || !audio
+                        || (video
+                            && (data.video_policy == VideoPolicy::Never
+                                || (data.video_policy == VideoPolicy::Mbid && data.stream)));
+                    let requirement = if data.stream {
+                        data.requirement.min(Requirement::ArtistTitle)
+                    } else {
+                        data.requirement
+                    };
+                    let allowed = !data.excluded
+                        && !data.skipped()
+                        && (data.stream
+                            || requirement == Requirement::Nothing
+                            || *mpv.get_property::<MpvStr>("filename").unwrap()
+                                != data.payload.track_metadata.track_name);
 
-                        if data.scrobble {
-                            let duration = mpv.get_property::<f64>("duration").unwrap();
-                            let speed = mpv.get_property::<f64>("speed").unwrap();
+                    data.play_time = PlayTime {
+                        speed: mpv.get_property::<f64>("speed").unwrap(),
+                        ..PlayTime::default()
+                    };
+                    update_play_time(&mpv, data, false);
 
-                            data.scrobble_deadline = Instant::now()
-                                + Duration::from_secs_f64(scrobble_duration!(duration, speed));
-                            data.payload.track_metadata.additional_info.duration_ms =
-                                (duration * 1000.0) as u64;
-                            timer = rx_handle
-                                .insert_source(
-                                    Timer::from_deadline(data.scrobble_deadline),
-                                    timer_event,
-                                )
-                                .unwrap();
-
-                            if data.online {
-                                data.payload.listened_at = None;
-                                scrobble(
-                                    "playing_now",
-                                    &data.payload,
-                                    data.online,
-                                    &data.token,
-                                    &data.cache_path,
-                                );
+                    // Single-file albums and CUE sheets are scrobbled chapter by chapter, but not
+                    // files too long to be albums, nor files tagged as one recording
+                    data.chapters = match duration {
+                        Some(duration)
+                            if !data.stream
+                                && !data.thresholds.is_long(duration)
+                                && (data.chapter_policy == ChapterPolicy::Always
+                                    || data
+                                        .payload
+                                        .track_metadata
+                                        .additional_info
+                                        .recording_mbid
+                                        .is_empty()) =>
+                        {
+                            chapter_starts(&mpv, data.chapter_policy)
+                        }
+                        _ => Vec::new(),
+                    };
+                    if !data.chapters.is_empty() {
+                        data.album = data.payload.track_metadata.clone();
+                        data.duration = duration.unwrap();
+                        data.chapter = mpv.get_property::<i64>("chapter").unwrap_or(-1);
+                        chapter_changed(&mpv, data);
+                    } else {
+                        if allowed {
+                            look_up(data);
+                        }
+                        data.scrobble =
+                            allowed && requirement.satisfied_by(&data.payload.track_metadata);
+                        data.scrobblable = data.scrobble;
+                        if data.scrobble || data.lookup_pending.is_some() {
+                            let duration = duration.filter(|_| !data.stream);
+                            match duration.map_or(Some(data.stream_threshold), |duration| {
+                                data.thresholds.threshold(duration)
+                            }) {
+                                Some(threshold) => data.threshold = threshold,
+                                None => {
+                                    data.scrobble = false;
+                                    data.scrobblable = false;
+                                    data.lookup_pending = None;
+                                }
                             }
+                            data.payload.track_metadata.additional_info.duration_ms =
+                                duration.map(|duration| (duration * 1000.0) as u64);
+
+                            playing_now(data);
                         }
                     }
+                    reschedule(data, &rx_handle);
                 }
                 None => break,
                 _ => {}
@@ -574,7 +2235,7 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
 
     #[cfg(feature = "connman")]
     {
-        data.online = {
+        let (online, metered) = {
             let (system_connection, _sender): (calloop_dbus::DBusSource<()>, _) =
                 calloop_dbus::DBusSource::new_system().unwrap();
             let connman_proxy =
@@ -583,12 +2244,22 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
                 .method_call("net.connman.Manager", "GetProperties", ())
                 .and_then(|r: (dbus::arg::PropMap,)| Ok(r.0))
                 .unwrap();
+            let services = connman_proxy
+                .method_call("net.connman.Manager", "GetServices", ())
+                .and_then(|r: (Vec<(dbus::Path<'static>, dbus::arg::PropMap)>,)| Ok(r.0))
+                .unwrap();
             system_connection
                 .add_match::<connman::NetConnmanManagerPropertyChanged, _>(
                     MatchRule::new_signal("net.connman.Manager", "PropertyChanged"),
                     |_, _, _| true,
                 )
                 .unwrap();
+            system_connection
+                .add_match::<connman::NetConnmanManagerServicesChanged, _>(
+                    MatchRule::new_signal("net.connman.Manager", "ServicesChanged"),
+                    |_, _, _| true,
+                )
+                .unwrap();
 
             let state = properties
                 .get("State")
@@ -597,43 +2268,182 @@ pub extern "C" fn mpv_open_cplugin(ctx: *mut mpv_handle) -> i8 {
                 .as_str()
                 .unwrap_or_default();
 
+            // `ServicesChanged` only carries the properties of services that changed, so whether
+            // every known service is connected and metered is kept here
+            let mut known_services: HashMap<dbus::Path<'static>, (bool, bool)> = services
+                .iter()
+                .map(|(path, properties)| {
+                    (
+                        path.clone(),
+                        (
+                            connman_service_connected(properties).unwrap_or(false),
+                            connman_service_metered(properties),
+                        ),
+                    )
+                })
+                .collect();
+            let metered = connman_metered(services.iter().map(|(path, _)| path), &known_services);
+
             handle
-                .insert_source(system_connection, |event, _metadata, data| {
+                .insert_source(system_connection, move |event, _metadata, data| {
                     if let Some(member) = event.member() {
                         if &*member == "PropertyChanged" {
                             let property: connman::NetConnmanManagerPropertyChanged =
                                 event.read_all().unwrap();
                             if property.name == "State" {
                                 let val = property.value.0.as_str().unwrap();
-                                data.online = val == "ready" || val == "online";
-                                if data.online {
-                                    import_cache(&data.token, &data.cache_path);
-                                    if data.scrobble {
-                                        data.payload.listened_at = None;
-                                        scrobble(
-                                            "playing_now",
-                                            &data.payload,
-                                            data.online,
-                                            &data.token,
-                                            &data.cache_path,
-                                        );
-                                    }
+                                let metered = data.metered;
+                                network_changed(data, val == "ready" || val == "online", metered);
+                            }
+                        } else if &*member == "ServicesChanged" {
+                            let services: connman::NetConnmanManagerServicesChanged =
+                                event.read_all().unwrap();
+                            for path in &services.removed {
+                                known_services.remove(path);
+                            }
+                            for (path, properties) in &services.changed {
+                                let service = known_services.entry(path.clone()).or_default();
+                                if let Some(connected) = connman_service_connected(properties) {
+                                    service.0 = connected;
                                 }
+                                if properties.contains_key("Type") {
+                                    service.1 = connman_service_metered(properties);
+                                }
+                            }
+                            // `changed` lists every service, in order
+                            let metered = connman_metered(
+                                services.changed.iter().map(|(path, _)| path),
+                                &known_services,
+                            );
+                            let online = data.online;
+                            network_changed(data, online, metered);
+                        }
+                    }
+                    None
+                })
+                .unwrap();
+            (state == "ready" || state == "online", metered)
+        };
+        data.online = online;
+        data.metered = metered;
+    }
+
+    #[cfg(feature = "networkmanager")]
+    {
+        let (online, metered) = {
+            let (system_connection, _sender): (calloop_dbus::DBusSource<()>, _) =
+                calloop_dbus::DBusSource::new_system().unwrap();
+            let nm_proxy = system_connection.with_proxy(
+                "org.freedesktop.NetworkManager",
+                "/org/freedesktop/NetworkManager",
+                Duration::from_secs(5),
+            );
+            let state: u32 = nm_proxy
+                .get("org.freedesktop.NetworkManager", "State")
+                .unwrap();
+            let metered: u32 = nm_proxy
+                .get("org.freedesktop.NetworkManager", "Metered")
+                .unwrap();
+            system_connection
+                .add_match::<PropertiesPropertiesChanged, _>(
+                    MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+                        .with_path("/org/freedesktop/NetworkManager"),
+                    |_, _, _| true,
+                )
+                .unwrap();
+
+            handle
+                .insert_source(system_connection, |event, _metadata, data| {
+                    if let Some(member) = event.member() {
+                        if &*member == "PropertiesChanged" {
+                            let properties: PropertiesPropertiesChanged = event.read_all().unwrap();
+                            if properties.interface_name == "org.freedesktop.NetworkManager" {
+                                let changed = &properties.changed_properties;
+                                let online = changed
+                                    .get("State")
+                                    .and_then(|state| state.0.as_u64())
+                                    .map_or(data.online, |state| {
+                                        state as u32 == NM_STATE_CONNECTED_GLOBAL
+                                    });
+                                let metered = changed
+                                    .get("Metered")
+                                    .and_then(|metered| metered.0.as_u64())
+                                    .map_or(data.metered, |metered| nm_metered(metered as u32));
+                                network_changed(data, online, metered);
                             }
                         }
                     }
                     None
                 })
                 .unwrap();
-            state == "ready" || state == "online"
+            (state == NM_STATE_CONNECTED_GLOBAL, nm_metered(metered))
         };
+        data.online = online;
+        data.metered = metered;
+    }
+
+    if data.batch {
+        handle
+            .insert_source(
+                Timer::from_duration(data.batch_interval),
+                |_event, _metadata, data| {
+                    if data.may_flush() {
+                        import_cache(data);
+                    }
+                    TimeoutAction::ToDuration(data.batch_interval)
+                },
+            )
+            .unwrap();
     }
+    // ListenBrainz forgets `playing_now` after a while, which long tracks outlast
+    handle
+        .insert_source(
+            Timer::from_duration(data.playing_now_interval),
+            |_event, _metadata, data| {
+                let now = Instant::now();
+                if let Some(at) = data.playing_now_at {
+                    if now >= at + data.playing_now_interval {
+                        playing_now(data);
+                    }
+                }
+                let next = data.playing_now_at.unwrap_or(now) + data.playing_now_interval;
+                TimeoutAction::ToInstant(if next > now {
+                    next
+                } else {
+                    now + data.playing_now_interval
+                })
+            },
+        )
+        .unwrap();
     drop(handle);
 
-    if data.online {
-        import_cache(&data.token, &data.cache_path);
+    if data.may_flush() {
+        import_cache(&mut data);
     }
 
     event_loop.run(None, &mut data, |_| {}).unwrap();
-    return 0;
+
+    if data.batch && data.may_flush() {
+        import_cache(&mut data);
+    }
+    let grace = Instant::now() + SHUTDOWN_GRACE;
+    while !data.http.is_idle() && Instant::now() < grace {
+        event_loop
+            .dispatch(grace.saturating_duration_since(Instant::now()), &mut data)
+            .unwrap();
+    }
+
+    // Whatever hasn't been submitted by the time mpv quits is cached for next time
+    let pending: Vec<Completion> = data.http.drain().collect();
+    for completion in pending {
+        complete(
+            &mut data,
+            completion,
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "mpv is shutting down",
+            )),
+        );
+    }
+    0
 }
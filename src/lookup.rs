@@ -0,0 +1,111 @@
+//! MBIDs for untagged files, from ListenBrainz's MBID mapping. Answers are kept on disk, so that
+//! every track is only looked up once.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::Request;
+
+/// The recording the MBID mapping found for an artist and title
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lookup {
+    pub artist_credit_name: String,
+    pub recording_name: String,
+    pub recording_mbid: String,
+    #[serde(default)]
+    pub release_mbid: String,
+    #[serde(default)]
+    pub artist_mbids: Vec<String>,
+}
+
+impl Lookup {
+    /// Answers are `{}` when nothing was found
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        serde_json::from_slice(body).ok()
+    }
+
+    /// How closely the recording matches what was looked up, from 0 to 1
+    pub fn confidence(&self, artist: &str, title: &str) -> f64 {
+        similarity(artist, &self.artist_credit_name).min(similarity(title, &self.recording_name))
+    }
+}
+
+pub fn request(artist: &str, title: &str, album: &str) -> Request {
+    let mut query = vec![("artist_name", artist), ("recording_name", title)];
+    if !album.is_empty() {
+        query.push(("release_name", album));
+    }
+    Request::get("/1/metadata/lookup/", &query)
+}
+
+/// Lookups are the same for artists, titles and albums that differ only in case and spacing
+pub fn key(artist: &str, title: &str, album: &str) -> String {
+    [artist, title, album]
+        .map(|s| {
+            s.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+        })
+        .join("\n")
+}
+
+/// Only letters and digits, so that punctuation and spacing don't count
+fn normalize(s: &str) -> Vec<char> {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// One minus the edit distance, relative to the longer of `a` and `b`
+fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (normalize(a), normalize(b));
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+/// Answers by `key`, including the lookups that found nothing
+#[derive(Debug, Default)]
+pub struct Cache {
+    path: PathBuf,
+    lookups: HashMap<String, Option<Lookup>>,
+}
+
+impl Cache {
+    pub fn load(path: PathBuf) -> Self {
+        let lookups = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+                eprintln!("Error reading the lookup cache: {}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { path, lookups }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Option<Lookup>> {
+        self.lookups.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, lookup: Option<Lookup>) {
+        self.lookups.insert(key, lookup);
+        if let Err(e) = std::fs::write(&self.path, serde_json::to_vec(&self.lookups).unwrap()) {
+            eprintln!("Error saving the lookup cache: {}", e);
+        }
+    }
+}
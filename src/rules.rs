@@ -0,0 +1,141 @@
+//! Include and exclude lists of paths and URLs, as globs or, prefixed with `re:`, regexes
+
+use regex::Regex;
+
+#[derive(Debug, Default)]
+pub struct PathRules {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl PathRules {
+    pub fn include(&mut self, pattern: &str) {
+        if let Some(regex) = compile(pattern) {
+            self.include.push(regex);
+        }
+    }
+
+    pub fn exclude(&mut self, pattern: &str) {
+        if let Some(regex) = compile(pattern) {
+            self.exclude.push(regex);
+        }
+    }
+
+    /// Whether `path` may be scrobbled: it must match an include rule, if there are any, and no
+    /// exclude rule
+    pub fn allows(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.is_match(path)))
+            && !self.exclude.iter().any(|rule| rule.is_match(path))
+    }
+}
+
+fn compile(pattern: &str) -> Option<Regex> {
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return None;
+    }
+    let regex = match pattern.strip_prefix("re:") {
+        Some(regex) => regex.to_string(),
+        None => glob_to_regex(pattern),
+    };
+    match Regex::new(&regex) {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            eprintln!("Invalid path rule {:?}: {}", pattern, e);
+            None
+        }
+    }
+}
+
+/// Globs match the whole path. `**` matches anything, `*` and `?` don't match across a `/`, and
+/// `[...]` is a character class.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                // Negated classes don't match across a `/` either
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push_str("^/");
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' || c == '^' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn double_star() {
+        assert!(matches("**/Podcasts/**", "/home/me/Podcasts/show/1.mp3"));
+        assert!(matches("**/Podcasts/**", "/Podcasts/1.mp3"));
+        assert!(!matches("**/Podcasts/**", "/home/me/Music/Podcasts.mp3"));
+        assert!(matches("/music/**", "/music/a/b/c.flac"));
+    }
+
+    #[test]
+    fn single_star() {
+        assert!(matches("/music/*.flac", "/music/a.flac"));
+        assert!(!matches("/music/*.flac", "/music/album/a.flac"));
+        assert!(matches("/music/*/*.flac", "/music/album/a.flac"));
+        // Globs match the whole path
+        assert!(!matches("*.flac", "/music/a.flac.part"));
+        assert!(matches("/music/?.flac", "/music/a.flac"));
+        assert!(!matches("/music/?.flac", "/music/ab.flac"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("/music/[abc]*", "/music/b.flac"));
+        assert!(!matches("/music/[abc]*", "/music/d.flac"));
+        assert!(matches("/music/[!.]*", "/music/a.flac"));
+        assert!(!matches("/music/[!.]*", "/music/.hidden"));
+        assert!(!matches("/music[!x]a.flac", "/music/a.flac"));
+        // Characters special to regexes are taken literally
+        assert!(matches("/music/[^]*", "/music/^a"));
+        assert!(matches("/music/(live) [+].mp3", "/music/(live) +.mp3"));
+    }
+
+    #[test]
+    fn rules() {
+        let mut rules = PathRules::default();
+        assert!(rules.allows("/anything"));
+        rules.include("/music/**");
+        rules.include("  ");
+        rules.exclude("**/Podcasts/**");
+        rules.exclude("re:^https?://");
+        rules.exclude("re:(");
+        assert!(rules.allows("/music/a.flac"));
+        assert!(!rules.allows("/music/Podcasts/1.mp3"));
+        assert!(!rules.allows("/videos/a.mkv"));
+        assert!(!rules.allows("https://example.org/stream"));
+    }
+}
@@ -0,0 +1,668 @@
+//! Readers for the tags embedded in audio files, for when mpv's `metadata` leaves something out.
+//! Every reader returns the tags as `(key, value)` pairs, with the container's own spelling of the
+//! keys and several values of one key joined with `;`.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use id3::{Content, Tag};
+
+pub fn read(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    file.rewind()?;
+    match &magic {
+        [b'f', b'L', b'a', b'C', ..] => read_flac(&mut file),
+        [b'O', b'g', b'g', b'S', ..] => read_ogg(&mut file),
+        [_, _, _, _, b'f', b't', b'y', b'p'] => read_mp4(&mut file),
+        [b'I', b'D', b'3', ..] => {
+            // APEv2 is sometimes appended to MP3s as well, and still worth reading when the ID3
+            // tag is broken
+            let mut tags = read_id3(path).unwrap_or_default();
+            tags.extend(read_ape(&mut file).unwrap_or_default());
+            Ok(tags)
+        }
+        _ => read_ape(&mut file),
+    }
+}
+
+fn read_u32_le(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().unwrap()))
+}
+
+fn read_u32_be(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().unwrap()))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Adds a value to the tag of the same key, if there is one
+fn push(tags: &mut Vec<(String, String)>, key: &str, value: &str) {
+    match tags.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+        Some((_, values)) => {
+            values.push(';');
+            values.push_str(value);
+        }
+        None => tags.push((key.to_string(), value.to_string())),
+    }
+}
+
+/// A Vorbis comment block, as found in FLAC, Ogg Vorbis and Opus
+fn parse_vorbis_comment(block: &[u8]) -> Option<Vec<(String, String)>> {
+    let vendor_len = read_u32_le(block)? as usize;
+    let mut rest = block.get(4 + vendor_len..)?;
+    let count = read_u32_le(rest)?;
+    rest = &rest[4..];
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        let len = read_u32_le(rest)? as usize;
+        let comment = rest.get(4..4 + len)?;
+        rest = &rest[4 + len..];
+        let Ok(comment) = std::str::from_utf8(comment) else {
+            continue;
+        };
+        if let Some((key, value)) = comment.split_once('=') {
+            push(&mut tags, key, value);
+        }
+    }
+    Some(tags)
+}
+
+fn read_flac(file: &mut (impl Read + Seek)) -> io::Result<Vec<(String, String)>> {
+    file.seek(SeekFrom::Start(4))?;
+    loop {
+        let mut header = [0; 4];
+        file.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        if header[0] & 0x7f == 4 {
+            let mut block = vec![0; len as usize];
+            file.read_exact(&mut block)?;
+            return parse_vorbis_comment(&block).ok_or_else(|| invalid("Vorbis comment"));
+        }
+        if last {
+            return Ok(Vec::new());
+        }
+        file.seek(SeekFrom::Current(len.into()))?;
+    }
+}
+
+/// The comment header is the second packet of the first logical stream
+fn read_ogg(file: &mut impl Read) -> io::Result<Vec<(String, String)>> {
+    let mut serial = None;
+    let mut packets = 0;
+    let mut packet = Vec::new();
+    loop {
+        let mut header = [0; 27];
+        file.read_exact(&mut header)?;
+        if &header[..4] != b"OggS" {
+            return Err(invalid("Ogg page"));
+        }
+        let mut segments = vec![0; header[26] as usize];
+        file.read_exact(&mut segments)?;
+        let mut body = vec![0; segments.iter().map(|&s| s as usize).sum()];
+        file.read_exact(&mut body)?;
+        let page_serial = read_u32_le(&header[14..]).unwrap();
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for &segment in &segments {
+            if packets == 1 {
+                packet.extend_from_slice(&body[offset..offset + segment as usize]);
+            }
+            offset += segment as usize;
+            // A segment shorter than 255 bytes ends a packet
+            if segment < 255 {
+                packets += 1;
+                if packets == 2 {
+                    let comment = packet
+                        .strip_prefix(b"\x03vorbis")
+                        .or_else(|| packet.strip_prefix(b"OpusTags"))
+                        .ok_or_else(|| invalid("Ogg comment header"))?;
+                    return parse_vorbis_comment(comment).ok_or_else(|| invalid("Vorbis comment"));
+                }
+            }
+        }
+    }
+}
+
+/// The boxes within `data`, as type and contents
+fn mp4_boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = read_u32_be(data)? as usize;
+        let kind = data.get(4..8)?;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                u64::from_be_bytes(data.get(8..16)?.try_into().unwrap()) as usize,
+            ),
+            size => (8, size),
+        };
+        let contents = data.get(header..size)?;
+        data = &data[size..];
+        Some((kind, contents))
+    })
+}
+
+fn mp4_child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    mp4_boxes(data)
+        .find(|(k, _)| *k == kind)
+        .map(|(_, contents)| contents)
+}
+
+/// The value of an item's `data` box, without its type and locale
+fn mp4_data(item: &[u8]) -> Option<&[u8]> {
+    mp4_child(item, b"data")?.get(8..)
+}
+
+fn read_mp4(file: &mut (impl Read + Seek)) -> io::Result<Vec<(String, String)>> {
+    let end = file.seek(SeekFrom::End(0))?;
+    file.rewind()?;
+    // `moov` may come after the media data, which is skipped over
+    let moov = loop {
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        let size = match read_u32_be(&header).unwrap() {
+            1 => {
+                let mut size = [0; 8];
+                file.read_exact(&mut size)?;
+                u64::from_be_bytes(size).checked_sub(16)
+            }
+            0 => return Ok(Vec::new()),
+            size => u64::from(size).checked_sub(8),
+        };
+        // Sizes come from the file, so they can't be trusted to fit in it
+        let remaining = end.saturating_sub(file.stream_position()?);
+        let Some(size) = size.filter(|&size| size <= remaining) else {
+            return Err(invalid("MP4 box size"));
+        };
+        if &header[4..] == b"moov" {
+            let mut moov = vec![0; size as usize];
+            file.read_exact(&mut moov)?;
+            break moov;
+        }
+        file.seek(SeekFrom::Current(size as i64))?;
+    };
+
+    let Some(ilst) = mp4_child(&moov, b"udta")
+        .and_then(|udta| mp4_child(udta, b"meta"))
+        .and_then(|meta| mp4_child(meta.get(4..)?, b"ilst"))
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut tags = Vec::new();
+    for (kind, item) in mp4_boxes(ilst) {
+        let key = match kind {
+            b"\xa9nam" => "title",
+            b"\xa9ART" => "artist",
+            b"\xa9alb" => "album",
+            b"aART" => "album_artist",
+            b"\xa9gen" => "genre",
+            b"trkn" | b"disk" => {
+                // Binary: reserved, number and total as 16 bit integers
+                let Some(number) = mp4_data(item).and_then(|data| data.get(2..4)) else {
+                    continue;
+                };
+                let number = u16::from_be_bytes([number[0], number[1]]).to_string();
+                let key = if kind == b"trkn" { "track" } else { "disc" };
+                push(&mut tags, key, &number);
+                continue;
+            }
+            // Freeform items like `----:com.apple.iTunes:MusicBrainz Track Id`
+            b"----" => {
+                let Some(name) = mp4_child(item, b"name").and_then(|name| name.get(4..)) else {
+                    continue;
+                };
+                let Ok(name) = std::str::from_utf8(name) else {
+                    continue;
+                };
+                for (kind, data) in mp4_boxes(item) {
+                    if kind == b"data" {
+                        if let Some(Ok(value)) = data.get(8..).map(std::str::from_utf8) {
+                            push(&mut tags, name, value);
+                        }
+                    }
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        if let Some(Ok(value)) = mp4_data(item).map(std::str::from_utf8) {
+            push(&mut tags, key, value);
+        }
+    }
+    Ok(tags)
+}
+
+/// APEv2 tags sit at the end of the file, possibly followed by an ID3v1 tag
+fn read_ape(file: &mut (impl Read + Seek)) -> io::Result<Vec<(String, String)>> {
+    let mut footer = [0; 32];
+    let mut end = file.seek(SeekFrom::End(0))?;
+    for id3v1 in [0, 128] {
+        if end < 32 + id3v1 {
+            return Ok(Vec::new());
+        }
+        file.seek(SeekFrom::Start(end - id3v1 - 32))?;
+        file.read_exact(&mut footer)?;
+        if &footer[..8] == b"APETAGEX" {
+            end -= id3v1;
+            break;
+        }
+    }
+    if &footer[..8] != b"APETAGEX" {
+        return Ok(Vec::new());
+    }
+
+    // The size covers the items and the footer
+    let size = u64::from(read_u32_le(&footer[12..]).unwrap());
+    let count = read_u32_le(&footer[16..]).unwrap();
+    if size < 32 || size > end {
+        return Err(invalid("APE tag size"));
+    }
+    let mut items = vec![0; size as usize - 32];
+    file.seek(SeekFrom::Start(end - size))?;
+    file.read_exact(&mut items)?;
+
+    let mut tags = Vec::new();
+    let mut rest = &items[..];
+    for _ in 0..count {
+        let (Some(len), Some(flags)) = (read_u32_le(rest), rest.get(4..).and_then(read_u32_le))
+        else {
+            break;
+        };
+        let Some(key_len) = rest.get(8..).and_then(|key| memchr::memchr(0, key)) else {
+            break;
+        };
+        let key = &rest[8..8 + key_len];
+        let Some(value) = rest.get(9 + key_len..9 + key_len + len as usize) else {
+            break;
+        };
+        rest = &rest[9 + key_len + len as usize..];
+        // Only UTF-8 items, not binary ones or links
+        if (flags >> 1) & 3 != 0 {
+            continue;
+        }
+        if let (Ok(key), Ok(value)) = (std::str::from_utf8(key), std::str::from_utf8(value)) {
+            push(&mut tags, key, &value.replace('\0', ";"));
+        }
+    }
+    Ok(tags)
+}
+
+fn read_id3(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let tag = Tag::read_from_path(path).map_err(|e| invalid(&e.to_string()))?;
+    let mut tags = Vec::new();
+    for f in tag.frames() {
+        match f.content() {
+            Content::ExtendedText(text) => {
+                push(&mut tags, &text.description, &text.value.replace('\0', ";"))
+            }
+            Content::Text(text) => {
+                let key = match f.id() {
+                    "TIT2" => "title",
+                    "TPE1" => "artist",
+                    "TALB" => "album",
+                    "TCON" => "genre",
+                    // `TPE2`, `TRCK`, `TPOS` and `TSRC` are understood as they are
+                    id => id,
+                };
+                push(&mut tags, key, &text.replace('\0', ";"));
+            }
+            Content::UniqueFileIdentifier(ufid) => {
+                if let Ok(id) = std::str::from_utf8(&ufid.identifier) {
+                    push(&mut tags, &ufid.owner_identifier, id);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn vorbis_comment(count: u32, comments: &[&str]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend(4u32.to_le_bytes());
+        block.extend(b"test");
+        block.extend(count.to_le_bytes());
+        for comment in comments {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        block
+    }
+
+    fn flac(comment: &[u8]) -> Vec<u8> {
+        let mut file = b"fLaC".to_vec();
+        // STREAMINFO, then the comment as the last block
+        file.extend([0, 0, 0, 34]);
+        file.extend([0; 34]);
+        file.push(0x84);
+        file.extend(&(comment.len() as u32).to_be_bytes()[1..]);
+        file.extend(comment);
+        file.extend([0xff, 0xf8, 0, 0]);
+        file
+    }
+
+    #[test]
+    fn flac_comments() {
+        // Comments without a `=` are left out
+        let comment = vorbis_comment(4, &["TITLE=Title", "ARTIST=A", "NO VALUE", "artist=B"]);
+        assert_eq!(
+            read_flac(&mut Cursor::new(flac(&comment))).unwrap(),
+            tags(&[("TITLE", "Title"), ("ARTIST", "A;B")])
+        );
+
+        let mut file = b"fLaC".to_vec();
+        file.extend([0x80, 0, 0, 34]);
+        file.extend([0; 34]);
+        assert_eq!(read_flac(&mut Cursor::new(file)).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn flac_errors() {
+        let file = flac(&vorbis_comment(1, &["TITLE=Title"]));
+        for len in [0, 4, 10, 50, file.len() - 10] {
+            assert!(
+                read_flac(&mut Cursor::new(&file[..len])).is_err(),
+                "{}",
+                len
+            );
+        }
+        // More comments than the block holds
+        let file = flac(&vorbis_comment(u32::MAX, &["TITLE=Title"]));
+        assert!(read_flac(&mut Cursor::new(file)).is_err());
+        let mut comment = vorbis_comment(1, &["TITLE=Title"]);
+        comment[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_flac(&mut Cursor::new(flac(&comment))).is_err());
+    }
+
+    fn ogg_page(serial: u32, segments: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend([0; 8]);
+        page.extend(serial.to_le_bytes());
+        page.extend([0; 8]);
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        page.extend(body);
+        page
+    }
+
+    /// The lacing values of a packet, which ends with a segment shorter than 255 bytes
+    fn lacing(len: usize) -> Vec<u8> {
+        let mut segments = vec![255; len / 255];
+        segments.push((len % 255) as u8);
+        segments
+    }
+
+    fn ogg(magic: &[u8], comment: &[u8]) -> Vec<u8> {
+        let mut packet = magic.to_vec();
+        packet.extend(comment);
+        let mut file = ogg_page(1, &[30], &[1; 30]);
+        // Another stream, which is skipped
+        file.extend(ogg_page(2, &[5], b"other"));
+        // The comment packet spans pages
+        let segments = lacing(packet.len());
+        let (first, second) = segments.split_at(1);
+        file.extend(ogg_page(1, first, &packet[..255]));
+        file.extend(ogg_page(1, second, &packet[255..]));
+        file
+    }
+
+    #[test]
+    fn ogg_comments() {
+        let long = format!("COMMENT={}", "x".repeat(300));
+        let comment = vorbis_comment(2, &["TITLE=Title", &long]);
+        let expected = tags(&[("TITLE", "Title"), ("COMMENT", &long[8..])]);
+        assert_eq!(
+            read_ogg(&mut Cursor::new(ogg(b"\x03vorbis", &comment))).unwrap(),
+            expected
+        );
+        assert_eq!(
+            read_ogg(&mut Cursor::new(ogg(b"OpusTags", &comment))).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn ogg_errors() {
+        let long = format!("COMMENT={}", "x".repeat(300));
+        let file = ogg(b"\x03vorbis", &vorbis_comment(1, &[&long]));
+        for len in [0, 20, 60, 100, file.len() - 10] {
+            assert!(read_ogg(&mut Cursor::new(&file[..len])).is_err(), "{}", len);
+        }
+        let file = ogg(b"\x03vorbis", &vorbis_comment(u32::MAX, &[&long]));
+        assert!(read_ogg(&mut Cursor::new(file)).is_err());
+        let file = ogg(b"\x03theora", &vorbis_comment(1, &[&long]));
+        assert!(read_ogg(&mut Cursor::new(file)).is_err());
+        let mut file = ogg(b"\x03vorbis", &vorbis_comment(1, &[&long]));
+        file[30 + 27 + 1] = b'X';
+        assert!(read_ogg(&mut Cursor::new(file)).is_err());
+    }
+
+    fn mp4_box(kind: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut b = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend(kind);
+        b.extend(contents);
+        b
+    }
+
+    fn mp4_item(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut contents = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        contents.extend(data);
+        mp4_box(kind, &mp4_box(b"data", &contents))
+    }
+
+    fn mp4(items: &[u8]) -> Vec<u8> {
+        let mut meta = vec![0; 4];
+        meta.extend(mp4_box(b"hdlr", &[0; 25]));
+        meta.extend(mp4_box(b"ilst", items));
+        let mut file = mp4_box(b"ftyp", b"M4A \0\0\0\0");
+        file.extend(mp4_box(b"mdat", &[0; 100]));
+        file.extend(mp4_box(
+            b"moov",
+            &mp4_box(b"udta", &mp4_box(b"meta", &meta)),
+        ));
+        file
+    }
+
+    fn mp4_freeform(name: &str, values: &[&str]) -> Vec<u8> {
+        let mut contents = mp4_box(b"mean", b"\0\0\0\0com.apple.iTunes");
+        contents.extend(mp4_box(b"name", format!("\0\0\0\0{}", name).as_bytes()));
+        for value in values {
+            let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+            data.extend(value.as_bytes());
+            contents.extend(mp4_box(b"data", &data));
+        }
+        mp4_box(b"----", &contents)
+    }
+
+    #[test]
+    fn mp4_items() {
+        let mut items = mp4_item(b"\xa9nam", b"Title");
+        items.extend(mp4_item(b"trkn", &[0, 0, 0, 3, 0, 10, 0, 0]));
+        items.extend(mp4_item(b"cpil", &[1]));
+        items.extend(mp4_freeform("MusicBrainz Artist Id", &["a", "b"]));
+        // Boxes too big for their parent end the list
+        items.extend(mp4_box(b"\xa9ART", &[]));
+        let len = items.len();
+        items[len - 8..len - 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            read_mp4(&mut Cursor::new(mp4(&items))).unwrap(),
+            tags(&[
+                ("title", "Title"),
+                ("track", "3"),
+                ("MusicBrainz Artist Id", "a;b")
+            ])
+        );
+    }
+
+    #[test]
+    fn mp4_errors() {
+        let file = mp4(&mp4_item(b"\xa9nam", b"Title"));
+        for len in [0, 4, 20, 100, file.len() - 10] {
+            assert!(read_mp4(&mut Cursor::new(&file[..len])).is_err(), "{}", len);
+        }
+        // `mdat` claims more than the file holds
+        let mut file = file;
+        file[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_mp4(&mut Cursor::new(&file)).is_err());
+        file[16..20].copy_from_slice(&4u32.to_be_bytes());
+        assert!(read_mp4(&mut Cursor::new(&file)).is_err());
+        // A 64 bit size
+        file[16..20].copy_from_slice(&1u32.to_be_bytes());
+        file.splice(24..24, u64::MAX.to_be_bytes());
+        assert!(read_mp4(&mut Cursor::new(&file)).is_err());
+    }
+
+    fn ape_item(flags: u32, key: &str, value: &[u8]) -> Vec<u8> {
+        let mut item = (value.len() as u32).to_le_bytes().to_vec();
+        item.extend(flags.to_le_bytes());
+        item.extend(key.as_bytes());
+        item.push(0);
+        item.extend(value);
+        item
+    }
+
+    fn ape(count: u32, items: &[u8]) -> Vec<u8> {
+        let mut file = vec![0xff; 100];
+        file.extend(items);
+        file.extend(b"APETAGEX");
+        file.extend(2000u32.to_le_bytes());
+        file.extend((items.len() as u32 + 32).to_le_bytes());
+        file.extend(count.to_le_bytes());
+        file.extend([0; 12]);
+        file
+    }
+
+    #[test]
+    fn ape_items() {
+        let mut items = ape_item(0, "Title", b"Title");
+        items.extend(ape_item(0, "Artist", b"A\0B"));
+        items.extend(ape_item(2, "Cover Art (Front)", b"\x89PNG"));
+        let file = ape(3, &items);
+        let expected = tags(&[("Title", "Title"), ("Artist", "A;B")]);
+        assert_eq!(read_ape(&mut Cursor::new(&file)).unwrap(), expected);
+
+        let mut id3v1 = file.clone();
+        id3v1.extend(b"TAG");
+        id3v1.extend([0; 125]);
+        assert_eq!(read_ape(&mut Cursor::new(id3v1)).unwrap(), expected);
+
+        // More items than there are end the list
+        let file = ape(u32::MAX, &items);
+        assert_eq!(read_ape(&mut Cursor::new(file)).unwrap(), expected);
+        let mut items = ape_item(0, "Title", b"Title");
+        items.extend(&ape_item(0, "Artist", b"A")[..8]);
+        let file = ape(2, &items);
+        assert_eq!(
+            read_ape(&mut Cursor::new(file)).unwrap(),
+            tags(&[("Title", "Title")])
+        );
+
+        assert_eq!(read_ape(&mut Cursor::new(vec![0; 10])).unwrap(), Vec::new());
+        assert_eq!(
+            read_ape(&mut Cursor::new(vec![0; 200])).unwrap(),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn ape_errors() {
+        let file = ape(1, &ape_item(0, "Title", b"Title"));
+        for size in [0, 31, u32::MAX] {
+            let mut file = file.clone();
+            let len = file.len();
+            file[len - 20..len - 16].copy_from_slice(&size.to_le_bytes());
+            assert!(read_ape(&mut Cursor::new(file)).is_err(), "{}", size);
+        }
+    }
+
+    fn id3_frame(id: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        // ID3v2.4 sizes are syncsafe, which doesn't change sizes below 128
+        frame.extend((contents.len() as u32).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(contents);
+        frame
+    }
+
+    fn id3(frames: &[u8]) -> Vec<u8> {
+        let mut file = b"ID3\x04\0\0".to_vec();
+        let size = frames.len() as u32;
+        file.extend([
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ]);
+        file.extend(frames);
+        file.extend([0xff, 0xfb, 0x90, 0x00]);
+        file.extend([0; 100]);
+        file
+    }
+
+    /// `read_id3` reads from a path, so the fixtures go through a file
+    fn read_bytes(name: &str, bytes: &[u8]) -> io::Result<Vec<(String, String)>> {
+        let path = std::env::temp_dir().join(format!(
+            "mpv-listenbrainz-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, bytes).unwrap();
+        let tags = read(&path);
+        std::fs::remove_file(&path).unwrap();
+        tags
+    }
+
+    #[test]
+    fn id3_frames() {
+        let mut frames = id3_frame(b"TIT2", b"\x03Title");
+        frames.extend(id3_frame(b"TPE1", b"\x03A\0B"));
+        frames.extend(id3_frame(b"TRCK", b"\x033/10"));
+        frames.extend(id3_frame(b"TXXX", b"\x03MusicBrainz Artist Id\0a\0b"));
+        frames.extend(id3_frame(b"UFID", b"http://musicbrainz.org\0id"));
+        assert_eq!(
+            read_bytes("frames", &id3(&frames)).unwrap(),
+            tags(&[
+                ("title", "Title"),
+                ("artist", "A;B"),
+                ("TRCK", "3/10"),
+                ("MusicBrainz Artist Id", "a;b"),
+                ("http://musicbrainz.org", "id"),
+            ])
+        );
+    }
+
+    #[test]
+    fn id3_errors() {
+        let mut file = id3(&id3_frame(b"TIT2", b"\x03Title"));
+        // A tag bigger than the file is left out, along with the APEv2 tag that isn't there
+        file[6..10].copy_from_slice(&[0x7f; 4]);
+        assert_eq!(read_bytes("oversized", &file).unwrap(), Vec::new());
+        assert_eq!(read_bytes("truncated", &file[..15]).unwrap(), Vec::new());
+        assert!(read_bytes("short", b"ID3").is_err());
+    }
+}
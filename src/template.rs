@@ -0,0 +1,162 @@
+//! Patterns like `%artist% - %title%`, used to pull metadata out of stream titles and file names
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses a pattern of literal text and `%field%` placeholders. Field names are lowercased,
+    /// and a `%` that doesn't start a placeholder is taken literally.
+    pub fn new(pattern: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('%') {
+            let after = &rest[start + 1..];
+            match after.find('%') {
+                Some(end)
+                    if end > 0
+                        && after[..end]
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    literal.push_str(&rest[..start]);
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(after[..end].to_ascii_lowercase()));
+                    rest = &after[end + 1..];
+                }
+                _ => {
+                    literal.push_str(&rest[..=start]);
+                    rest = after;
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Self { parts }
+    }
+
+    /// Matches the whole of `input`, returning the trimmed value of every field. Fields take as
+    /// little as they can, so the last field gets whatever is left over.
+    pub fn captures<'a>(&'a self, input: &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+        let mut captures = Vec::new();
+        capture(&self.parts, input, &mut captures).then_some(captures)
+    }
+}
+
+fn capture<'a>(parts: &'a [Part], input: &'a str, captures: &mut Vec<(&'a str, &'a str)>) -> bool {
+    match parts.split_first() {
+        None => input.is_empty(),
+        Some((Part::Literal(literal), rest)) => input
+            .strip_prefix(literal.as_str())
+            .is_some_and(|input| capture(rest, input, captures)),
+        Some((Part::Field(name), rest)) => {
+            let ends: Box<dyn Iterator<Item = usize>> = match rest.first() {
+                None => Box::new(std::iter::once(input.len())),
+                Some(Part::Literal(literal)) => Box::new(memchr::memmem::find_iter(
+                    input.as_bytes(),
+                    literal.as_bytes(),
+                )),
+                Some(Part::Field(_)) => Box::new(input.char_indices().map(|(i, _)| i).skip(1)),
+            };
+            for end in ends {
+                // Fields are never empty
+                if end == 0 {
+                    continue;
+                }
+                let len = captures.len();
+                if capture(rest, &input[end..], captures) {
+                    captures.push((name.as_str(), input[..end].trim()));
+                    return true;
+                }
+                captures.truncate(len);
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field<'a>(captures: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+        captures
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|&(_, value)| value)
+    }
+
+    #[test]
+    fn artist_title() {
+        let template = Template::new("%artist% - %title%");
+        let captures = template.captures("Daft Punk - One More Time").unwrap();
+        assert_eq!(field(&captures, "artist"), Some("Daft Punk"));
+        assert_eq!(field(&captures, "title"), Some("One More Time"));
+        assert_eq!(template.captures("Daft Punk – One More Time"), None);
+    }
+
+    #[test]
+    fn backtracking() {
+        // The first separator belongs to the artist only if the rest still matches
+        let template = Template::new("%artist% - %title% (%album%)");
+        let captures = template.captures("A - B - C (D)").unwrap();
+        assert_eq!(field(&captures, "artist"), Some("A"));
+        assert_eq!(field(&captures, "title"), Some("B - C"));
+        assert_eq!(field(&captures, "album"), Some("D"));
+
+        let template = Template::new("%artist% - %title% - %album%");
+        let captures = template.captures("A - B - C - D").unwrap();
+        assert_eq!(field(&captures, "artist"), Some("A"));
+        assert_eq!(field(&captures, "title"), Some("B"));
+        assert_eq!(field(&captures, "album"), Some("C - D"));
+
+        // Fields are never empty, so a leading separator is part of the artist
+        let template = Template::new("%artist%-%title%");
+        let captures = template.captures("-A-B").unwrap();
+        assert_eq!(field(&captures, "artist"), Some("-A"));
+        assert_eq!(field(&captures, "title"), Some("B"));
+    }
+
+    #[test]
+    fn adjacent_fields() {
+        let template = Template::new("%tracknumber%%title%");
+        let captures = template.captures("01 Intro").unwrap();
+        assert_eq!(field(&captures, "tracknumber"), Some("0"));
+        assert_eq!(field(&captures, "title"), Some("1 Intro"));
+    }
+
+    #[test]
+    fn literals() {
+        let template = Template::new("%title% by %Artist%");
+        let captures = template.captures("Song by Someone").unwrap();
+        assert_eq!(field(&captures, "artist"), Some("Someone"));
+        assert_eq!(template.captures("Song from Someone"), None);
+
+        // A `%` that doesn't start a placeholder is literal
+        let template = Template::new("100% %title% %not a field%");
+        let captures = template.captures("100% Pure %not a field%").unwrap();
+        assert_eq!(field(&captures, "title"), Some("Pure"));
+        assert_eq!(template.captures("100 Pure %not a field%"), None);
+    }
+
+    #[test]
+    fn whole_input() {
+        let template = Template::new("%artist%/%title%");
+        assert_eq!(template.captures("A/"), None);
+        assert_eq!(template.captures("A"), None);
+        assert!(Template::new("Station ID").captures("Station ID").is_some());
+        assert_eq!(Template::new("Station ID").captures("Station ID 2"), None);
+    }
+}